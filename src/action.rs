@@ -2,62 +2,182 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::condition::{query_values, HostPattern};
 use crate::config::*;
+use crate::connector::{UpstreamProtocol, UpstreamTls};
 use crate::errors::Error;
-use crate::proxy::Proxy;
+use crate::headers::ForwardedHeaders;
+use crate::proxy::{
+    HealthCheck, HostHeader, Proxy, ProxyOptions, ProxyRegistry, Retry, Strategy, Timeouts,
+    Upgrade, MAX_WEIGHT,
+};
+use crate::validation::{Issue, Report};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
 use salvo::prelude::*;
+use std::sync::Arc;
+
+// Inserted in the request extensions when a request is routed without being
+// served (see the `route` command): proxy actions then only report where the
+// request would be forwarded to, in the `DRY_RUN_PROXY_TARGET` header.
+#[derive(Clone, Copy, Debug)]
+pub struct DryRun;
+
+pub const DRY_RUN_PROXY_TARGET: &str = "x-dry-run-proxy-target";
+
+// The characters escaped in the path parameters, decoded by salvo: the URL path
+// percent-encode set, and `%`.
+const PATH_PARAM: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+// A value with `<name>` placeholders, replaced with the host labels, the query
+// values and the path parameters captured by a rule. Catch-all parameters such
+// as `<**rest>` are referenced without their stars: `<rest>`.
+pub struct Template {
+    value: String,
+    // Salvo filters cannot set parameters: the host labels and the query
+    // values are captured again when the request is handled.
+    host: Option<HostPattern>,
+    query: Vec<String>,
+}
+
+impl Template {
+    pub fn new(value: &str, rule: &ConfigRule) -> Template {
+        // An invalid host is reported by the router.
+        let host = rule
+            .host
+            .as_deref()
+            .and_then(|host| HostPattern::parse(host).ok());
+
+        Template {
+            value: value.to_string(),
+            host,
+            query: rule
+                .query
+                .iter()
+                .flatten()
+                .map(|q| q.name.clone())
+                .collect(),
+        }
+    }
+
+    pub fn render(&self, req: &Request) -> String {
+        if !self.value.contains('<') {
+            return self.value.clone();
+        }
+
+        let mut params = self
+            .host
+            .as_ref()
+            .and_then(|host| host.request_captures(req))
+            .unwrap_or_default();
+
+        for name in &self.query {
+            if let Some(value) = query_values(req, name).first() {
+                let value: String =
+                    url::form_urlencoded::byte_serialize(value.as_bytes()).collect();
+                params.push((name.clone(), value));
+            }
+        }
+
+        for (key, value) in req.params().iter() {
+            let value = utf8_percent_encode(value, PATH_PARAM).to_string();
+            params.push((key.trim_start_matches('*').to_string(), value));
+        }
+
+        substitute(&self.value, &params)
+    }
+}
+
+// Replaces the placeholders of `value` in a single pass: the ones found in the
+// parameter values are left as they are.
+fn substitute(value: &str, params: &[(String, String)]) -> String {
+    let mut rendered = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('<') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let param = rest.find('>').and_then(|end| {
+            params
+                .iter()
+                .find(|(key, _)| key == &rest[1..end])
+                .map(|(_, value)| (end, value))
+        });
+        match param {
+            Some((end, value)) => {
+                rendered.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                rendered.push('<');
+                rest = &rest[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
 
 pub struct RedirectAction {
-    redirect_to: String,
-    has_params: bool,
+    redirect_to: Template,
     status_code: StatusCode,
 }
 
 #[handler]
 impl RedirectAction {
-    pub fn new(rule: &ConfigRule) -> RedirectAction {
+    pub fn new(rule: &ConfigRule) -> Result<RedirectAction, Report> {
+        let mut report = Report::new();
+
         if rule.redirect_to.is_none() {
-            tracing::error!(target: "RedirectAction", rule = rule.name, "Invalid `redirect_to` value");
-            std::process::exit(1);
+            report.push(Issue::rule(rule, "redirect_to", "missing value"));
         }
 
         let status_code = match StatusCode::from_u16(
             rule.redirect_status
                 .unwrap_or(StatusCode::TEMPORARY_REDIRECT.as_u16()),
         ) {
-            Ok(code) => code,
+            Ok(code) if code.is_redirection() => code,
+            Ok(code) => {
+                report.push(Issue::rule(
+                    rule,
+                    "redirect_status",
+                    format!("the status code {} is not for redirects", code.as_u16()),
+                ));
+                return Err(report);
+            }
             Err(_) => {
-                tracing::error!(target: "RedirectAction", rule=rule.name, value=rule.redirect_status.unwrap(), "Invalid `redirect_status`");
-                std::process::exit(1);
+                report.push(Issue::rule(
+                    rule,
+                    "redirect_status",
+                    format!("invalid status code {}", rule.redirect_status.unwrap()),
+                ));
+                return Err(report);
             }
         };
 
-        if !status_code.is_redirection() {
-            tracing::error!(target: "RedirectAction", rule=rule.name, value=rule.redirect_status.unwrap(), "The status code is not for redirects");
-            std::process::exit(1);
-        }
+        let redirect_to = match rule.redirect_to.as_deref() {
+            Some(redirect_to) => redirect_to,
+            None => return Err(report),
+        };
 
         tracing::info!(target: "RedirectAction", rule=rule.name, url=rule.redirect_to, status_code= status_code.as_u16(),"creating a RedirectAction handler");
 
-        RedirectAction {
-            redirect_to: rule.redirect_to.as_deref().unwrap().to_string(),
-            has_params: rule.path.is_some() && rule.path.as_ref().unwrap().contains('<'),
-            status_code: status_code,
-        }
+        report.check(RedirectAction {
+            redirect_to: Template::new(redirect_to, rule),
+            status_code,
+        })
     }
 
     fn handle(&self, req: &mut Request, res: &mut Response) {
-        match self.has_params {
-            false => self.redirect(res, &self.redirect_to),
-            true => {
-                let mut redirect_to = self.redirect_to.clone();
-                for key in req.params().keys() {
-                    redirect_to =
-                        redirect_to.replace(&format!("<{}>", key), req.param(key).unwrap());
-                }
-                self.redirect(res, &redirect_to)
-            }
-        }
+        self.redirect(res, &self.redirect_to.render(req))
     }
 
     fn redirect(&self, res: &mut Response, uri: &str) {
@@ -70,31 +190,290 @@ impl RedirectAction {
     }
 }
 
+// How the path of a proxied request is changed: its prefix replaced, or the
+// whole path rewritten with a template. The query string is kept, unless the
+// template has one.
+enum PathRewrite {
+    Prefix {
+        strip: Option<String>,
+        add: Option<String>,
+    },
+    Template(Template),
+}
+
+fn valid_path(value: &str, name: &str) -> Result<(), String> {
+    match value.starts_with('/') {
+        true => Ok(()),
+        false => Err(format!("`{}` must start with `/`", name)),
+    }
+}
+
+impl PathRewrite {
+    fn new(rule: &ConfigRule) -> Result<PathRewrite, String> {
+        let config = rule.proxy_path.clone().unwrap_or_default();
+
+        if let Some(rewrite) = &config.rewrite {
+            if config.strip_prefix.is_some() || config.add_prefix.is_some() {
+                return Err(
+                    "`rewrite` cannot be used with `strip_prefix` or `add_prefix`".to_string(),
+                );
+            }
+            valid_path(rewrite, "rewrite")?;
+            return Ok(PathRewrite::Template(Template::new(rewrite, rule)));
+        }
+
+        if let Some(strip) = &config.strip_prefix {
+            valid_path(strip, "strip_prefix")?;
+        }
+        if let Some(add) = &config.add_prefix {
+            valid_path(add, "add_prefix")?;
+        }
+        Ok(PathRewrite::Prefix {
+            strip: config
+                .strip_prefix
+                .map(|strip| strip.trim_end_matches('/').to_string()),
+            add: config
+                .add_prefix
+                .map(|add| add.trim_end_matches('/').to_string()),
+        })
+    }
+
+    // The path and query of the request sent to the upstream.
+    fn path_and_query(&self, req: &Request) -> String {
+        let path = req.uri().path();
+        let path = match self {
+            PathRewrite::Prefix { strip, add } => {
+                // Prefixes are stripped on segment boundaries only.
+                let path = match strip.as_deref().and_then(|strip| path.strip_prefix(strip)) {
+                    Some("") => "/",
+                    Some(rest) if rest.starts_with('/') => rest,
+                    _ => path,
+                };
+                format!("{}{}", add.as_deref().unwrap_or_default(), path)
+            }
+            PathRewrite::Template(template) => template.render(req),
+        };
+
+        match req.uri().query() {
+            Some(query) if !path.contains('?') => format!("{}?{}", path, query),
+            _ => path,
+        }
+    }
+}
+
 pub struct ProxyAction {
-    proxy: Proxy,
+    proxy: Arc<Proxy>,
+    path: PathRewrite,
 }
 
 #[handler]
 impl ProxyAction {
-    pub fn new(rule: &ConfigRule) -> ProxyAction {
-        if rule.proxy_url.is_none() {
-            tracing::error!(target: "ProxyAction", rule = rule.name, "Invalid `proxy_url` value");
-            std::process::exit(1);
+    // The proxy of the rule is taken from the registry when another listener
+    // already created it.
+    pub fn new(
+        rule: &ConfigRule,
+        server: &ConfigServer,
+        registry: &ProxyRegistry,
+    ) -> Result<ProxyAction, Report> {
+        let path = PathRewrite::new(rule).map_err(|e| Issue::rule(rule, "proxy_path", e))?;
+
+        if let Some(proxy) = registry.get(&rule.name) {
+            return Ok(ProxyAction { proxy, path });
+        }
+
+        let mut report = Report::new();
+
+        let protocol = match rule
+            .upstream_protocol
+            .as_deref()
+            .map(UpstreamProtocol::parse)
+            .transpose()
+        {
+            Ok(protocol) => protocol.unwrap_or_default(),
+            Err(e) => {
+                report.push(Issue::rule(rule, "upstream_protocol", e));
+                UpstreamProtocol::default()
+            }
+        };
+
+        let tls = match rule
+            .proxy_tls
+            .as_ref()
+            .map(|tls| UpstreamTls::new(tls, protocol))
+            .transpose()
+        {
+            Ok(tls) => tls,
+            Err(e) => {
+                report.push(Issue::rule(rule, "proxy_tls", e));
+                None
+            }
+        };
+
+        let host_header = match rule
+            .proxy_host
+            .as_deref()
+            .map(HostHeader::parse)
+            .transpose()
+        {
+            Ok(host_header) => host_header,
+            Err(e) => {
+                report.push(Issue::rule(rule, "proxy_host", e));
+                None
+            }
+        };
+
+        let upstreams = match &rule.proxy_url {
+            Some(proxy_url) => proxy_url.upstreams(),
+            None => return Err(Issue::rule(rule, "proxy_url", "missing value").into()),
+        };
+        if upstreams.is_empty() {
+            report.push(Issue::rule(rule, "proxy_url", "no upstreams"));
         }
+        for (url, weight) in &upstreams {
+            if *weight == 0 || *weight > MAX_WEIGHT {
+                report.push(Issue::rule(
+                    rule,
+                    "proxy_url",
+                    format!(
+                        "the weight of `{}` must be between 1 and {}",
+                        url, MAX_WEIGHT
+                    ),
+                ));
+            }
+            // ALPN needs TLS, and h2c is the cleartext HTTP/2.
+            let https = url.starts_with("https://");
+            match protocol {
+                UpstreamProtocol::H2 if !https => report.push(Issue::rule(
+                    rule,
+                    "upstream_protocol",
+                    format!("`h2` requires an https upstream, not `{}`", url),
+                )),
+                UpstreamProtocol::H2c if https => report.push(Issue::rule(
+                    rule,
+                    "upstream_protocol",
+                    format!("`h2c` requires a cleartext upstream, not `{}`", url),
+                )),
+                _ => {}
+            }
+        }
+
+        let strategy = match Strategy::parse(&rule.balance.clone().unwrap_or_default()) {
+            Ok(strategy) => Some(strategy),
+            Err(e) => {
+                report.push(Issue::rule(rule, "balance", e));
+                None
+            }
+        };
 
-        tracing::info!(target: "ProxyAction", rule=rule.name, url=rule.proxy_url, "creating a ProxyAction handler");
+        let health_check = match HealthCheck::parse(&rule.health_check.clone().unwrap_or_default())
+        {
+            Ok(health_check) => Some(health_check),
+            Err(e) => {
+                report.push(Issue::rule(rule, "health_check", e));
+                None
+            }
+        };
 
-        match Proxy::create(rule.proxy_url.as_deref().unwrap()) {
-            Ok(proxy) => ProxyAction { proxy: proxy },
+        let retry = match rule.retry.as_ref().map(Retry::parse).transpose() {
+            Ok(retry) => Some(retry),
+            Err(e) => {
+                report.push(Issue::rule(rule, "retry", e));
+                None
+            }
+        };
+
+        let timeouts = match Timeouts::parse(rule.timeouts.as_ref(), server.timeouts.as_ref()) {
+            Ok(timeouts) => Some(timeouts),
+            Err(e) => {
+                report.push(Issue::rule(rule, "timeouts", e));
+                None
+            }
+        };
+
+        let upgrade = match Upgrade::parse(&rule.upgrade.clone().unwrap_or_default()) {
+            Ok(Some(_)) if protocol != UpstreamProtocol::Http1 => {
+                report.push(Issue::rule(
+                    rule,
+                    "upgrade",
+                    "upgrades require the `http1` upstream protocol",
+                ));
+                None
+            }
+            Ok(upgrade) => Some(upgrade),
             Err(e) => {
-                tracing::error!(target: "ProxyAction", rule = rule.name, url=rule.proxy_url, error=e.to_string(), "Invalid proxy_url");
-                std::process::exit(1);
+                report.push(Issue::rule(rule, "upgrade", e));
+                None
             }
+        };
+
+        let (strategy, health_check, retry, timeouts, upgrade) =
+            match (strategy, health_check, retry, timeouts, upgrade) {
+                (
+                    Some(strategy),
+                    Some(health_check),
+                    Some(retry),
+                    Some(timeouts),
+                    Some(upgrade),
+                ) if report.is_empty() => (strategy, health_check, retry, timeouts, upgrade),
+                _ => return Err(report),
+            };
+
+        tracing::info!(target: "ProxyAction", rule=rule.name, url=?rule.proxy_url, "creating a ProxyAction handler");
+
+        let options = ProxyOptions {
+            strategy,
+            health_check,
+            retry,
+            timeouts,
+            forwarded_headers: ForwardedHeaders::new(
+                &rule.forwarded_headers.clone().unwrap_or_default(),
+            ),
+            host_header: host_header.unwrap_or_default(),
+            tls,
+            protocol,
+            pool: rule.pool.clone().unwrap_or_default(),
+            upgrade,
+        };
+
+        match Proxy::create(&upstreams, options) {
+            Ok(proxy) => {
+                let proxy = Arc::new(proxy);
+                registry.insert(&rule.name, proxy.clone());
+                Ok(ProxyAction { proxy, path })
+            }
+            Err(e) => Err(Issue::rule(rule, "proxy_url", e.to_string()).into()),
         }
     }
 
     async fn handle(&self, req: &mut Request, res: &mut Response) -> Result<(), Error> {
-        self.proxy.handle(req, res).await
+        if req.extensions().get::<DryRun>().is_some() {
+            let target = self.proxy.target(&self.path.path_and_query(req));
+            res.add_header(DRY_RUN_PROXY_TARGET, target, true).ok();
+            return Ok(());
+        }
+
+        let path = self.path.path_and_query(req);
+        self.proxy.handle(req, res, &path).await
+    }
+}
+
+// Reports the health of the upstreams of every proxy rule, as JSON. It is
+// meant to be served by an internal listener.
+pub struct HealthAction {
+    registry: ProxyRegistry,
+}
+
+#[handler]
+impl HealthAction {
+    pub fn new(rule: &ConfigRule, registry: &ProxyRegistry) -> HealthAction {
+        tracing::info!(target: "HealthAction", rule=rule.name, "creating a HealthAction handler");
+        HealthAction {
+            registry: registry.clone(),
+        }
+    }
+
+    fn handle(&self, res: &mut Response) {
+        res.render(Json(self.registry.status()));
     }
 }
 
@@ -103,36 +482,91 @@ mod tests {
     use crate::config::*;
     use crate::routers;
     use salvo::http::StatusCode;
-    use salvo::test::TestClient;
+    use salvo::prelude::Service;
+    use salvo::test::{ResponseExt, TestClient};
+
+    #[test]
+    fn test_substitute() {
+        let params = [
+            ("a".to_string(), "<b>".to_string()),
+            ("b".to_string(), "2".to_string()),
+        ];
+        assert_eq!(super::substitute("/<a>/<b>", &params), "/<b>/2");
+        assert_eq!(super::substitute("<<b>>", &params), "<2>");
+        assert_eq!(super::substitute("/<c>/<b", &params), "/<c>/<b");
+    }
 
     #[tokio::test]
     async fn test_redirect_action() {
-        let config = Config::create_from_filename("tests/configs/004_redirect_action.yaml");
+        let config =
+            Config::create_from_filename("tests/configs/004_redirect_action.yaml").unwrap();
 
-        let resp = TestClient::get(format!("http://127.0.0.1:5800/test1"))
-            .send(routers::routers(&config.rules))
+        let resp = TestClient::get("http://127.0.0.1:5800/test1")
+            .send(routers::routers(&config.rules, &config.server).unwrap())
             .await;
         assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
         assert!(resp.headers().contains_key("location"));
         assert_eq!(resp.headers()["location"], "test");
 
-        let resp = TestClient::post(format!("http://127.0.0.1:5800/test2/42/b/hello"))
-            .send(routers::routers(&config.rules))
+        let resp = TestClient::post("http://127.0.0.1:5800/test2/42/b/hello")
+            .send(routers::routers(&config.rules, &config.server).unwrap())
             .await;
         assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
         assert!(resp.headers().contains_key("location"));
         assert_eq!(resp.headers()["location"], "test242bhello");
 
-        let resp = TestClient::post(format!("http://127.0.0.1:5800/test3/42/b/hello"))
-            .send(routers::routers(&config.rules))
+        let resp = TestClient::post("http://127.0.0.1:5800/test3/42/b/hello")
+            .send(routers::routers(&config.rules, &config.server).unwrap())
             .await;
         assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
         assert!(resp.headers().contains_key("location"));
         assert_eq!(resp.headers()["location"], "/test3?a=42&b=hello");
 
-        let resp = TestClient::post(format!("http://127.0.0.1:5800/test4"))
-            .send(routers::routers(&config.rules))
+        // The values are not rendered again.
+        let resp = TestClient::post("http://127.0.0.1:5800/test3/%3Cc%3E/b/hello")
+            .send(routers::routers(&config.rules, &config.server).unwrap())
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(resp.headers()["location"], "/test3?a=%3Cc%3E&b=hello");
+
+        let resp = TestClient::post("http://127.0.0.1:5800/test4")
+            .send(routers::routers(&config.rules, &config.server).unwrap())
             .await;
         assert_eq!(resp.status_code().unwrap(), StatusCode::PERMANENT_REDIRECT);
     }
+
+    #[tokio::test]
+    async fn test_proxy_rewrite() {
+        crate::proxy::tests::upstream("127.0.0.1:5918");
+
+        let config = Config::create_from_filename("tests/configs/021_proxy_rewrite.yaml").unwrap();
+        let service = Service::new(routers::routers(&config.rules, &config.server).unwrap());
+
+        for (url, path, host) in [
+            (
+                "http://127.0.0.1:5800/elk/mozilla.social/@user?page=2",
+                "/mozilla.social/@user?page=2",
+                "127.0.0.1:5918",
+            ),
+            (
+                "http://127.0.0.1:5800/strip/timelines/home",
+                "/api/v1/timelines/home",
+                "mozilla.social",
+            ),
+            ("http://127.0.0.1:5800/strip", "/api/v1/", "mozilla.social"),
+            (
+                "http://127.0.0.1:5800/keep/path",
+                "/keep/path",
+                "example.com",
+            ),
+        ] {
+            let mut resp = TestClient::get(url)
+                .add_header("host", "example.com", true)
+                .send(&service)
+                .await;
+            assert_eq!(resp.status_code().unwrap(), StatusCode::OK);
+            assert_eq!(resp.headers()["x-host"], host);
+            assert_eq!(resp.take_string().await.unwrap(), path);
+        }
+    }
 }
@@ -0,0 +1,360 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::action::{DryRun, DRY_RUN_PROXY_TARGET};
+use crate::config::{Config, ConfigRule};
+use crate::errors::Error;
+use crate::proxy::ProxyRegistry;
+use crate::routers;
+use crate::server;
+use crate::validation::{Issue, Report};
+use clap::{Parser, Subcommand};
+use salvo::prelude::{Request, Service, StatusCode};
+use salvo::routing::PathState;
+
+#[derive(Parser, Debug)]
+#[command(
+    about = "A simple routing tool",
+    args_conflicts_with_subcommands = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// The configuration file, when no command is given the server is started
+    config: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Start the server
+    Serve {
+        /// The configuration file
+        config: String,
+    },
+
+    /// Validate a configuration file, reporting every invalid rule
+    Check {
+        /// The configuration file
+        config: String,
+    },
+
+    /// Show which rule would handle a request, without contacting any upstream
+    Route {
+        /// The configuration file
+        config: String,
+        /// The request method
+        method: String,
+        /// The request URL
+        url: String,
+        /// A request header, as `name: value`
+        #[arg(short = 'H', long = "header")]
+        headers: Vec<String>,
+        /// Only consider the rules of the listener bound to this address
+        #[arg(short = 'l', long)]
+        listener: Option<String>,
+    },
+}
+
+// The outcome of routing a request with the `route` command.
+#[derive(Debug)]
+pub struct Routed {
+    pub rule: String,
+    pub action: String,
+    pub status: StatusCode,
+    pub location: Option<String>,
+    pub proxy_target: Option<String>,
+}
+
+pub async fn run() {
+    let cli = Cli::parse();
+
+    match (cli.command, cli.config) {
+        (Some(Command::Serve { config }), _) | (None, Some(config)) => serve(&config).await,
+        (Some(Command::Check { config }), _) => {
+            std::process::exit(check(&config));
+        }
+        (
+            Some(Command::Route {
+                config,
+                method,
+                url,
+                headers,
+                listener,
+            }),
+            _,
+        ) => {
+            std::process::exit(route(&config, listener.as_deref(), &method, &url, &headers).await);
+        }
+        (None, None) => {
+            tracing_subscriber::fmt().init();
+            tracing::error!(target: "Config", "no configuration file");
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn serve(filename: &str) {
+    tracing_subscriber::fmt().init();
+
+    let config = Config::create(filename);
+    server::run(&config).await
+}
+
+fn load(filename: &str) -> Result<Config, Report> {
+    match Config::create_from_filename(filename) {
+        Ok(config) => Ok(config),
+        Err(Error::InvalidConfig(report)) => Err(report),
+        Err(e) => Err(Issue::new(e.to_string(), None).into()),
+    }
+}
+
+pub fn validate(filename: &str) -> Result<Config, Report> {
+    let config = load(filename)?;
+    server::create_services(&config.server, &config.rules, &Default::default())?;
+    Ok(config)
+}
+
+fn check(filename: &str) -> i32 {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::ERROR)
+        .init();
+
+    match validate(filename) {
+        Ok(config) => {
+            println!(
+                "{}: {} rules, the configuration is valid",
+                filename,
+                config.rules.len()
+            );
+            0
+        }
+        Err(report) => {
+            println!("{}: the configuration is invalid", filename);
+            println!("{}", report);
+            1
+        }
+    }
+}
+
+fn request(method: &str, url: &str, headers: &[String]) -> Result<Request, String> {
+    let uri = url
+        .parse::<http::Uri>()
+        .map_err(|e| format!("invalid URL `{}`: {}", url, e))?;
+
+    let mut builder = hyper::Request::builder().method(method).uri(uri.clone());
+    if let Some(authority) = uri.authority() {
+        builder = builder.header(http::header::HOST, authority.as_str());
+    }
+
+    for header in headers {
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| format!("invalid header `{}`, expected `name: value`", header))?;
+        builder = builder.header(name.trim(), value.trim());
+    }
+
+    let mut req: Request = builder
+        .body(hyper::Body::empty())
+        .map_err(|e| e.to_string())?
+        .into();
+    req.extensions_mut().insert(DryRun);
+    Ok(req)
+}
+
+// The rules served by the listener bound to `bind`, or all of them.
+fn listener_rules<'a>(
+    config: &'a Config,
+    bind: Option<&str>,
+) -> Result<Vec<&'a ConfigRule>, Report> {
+    let Some(bind) = bind else {
+        return Ok(config.rules.iter().collect());
+    };
+
+    match config.server.listeners().iter().find(|l| l.bind == bind) {
+        Some(listener) => routers::listener_rules(listener, &config.rules),
+        None => Err(Issue::new(format!("no listener bound to `{}`", bind), None).into()),
+    }
+}
+
+// Finds the first rule matching the request, in the same order the router
+// uses, and runs it in dry-run mode.
+pub async fn route_request(
+    config: &Config,
+    listener: Option<&str>,
+    method: &str,
+    url: &str,
+    headers: &[String],
+) -> Result<Option<Routed>, Report> {
+    let registry = ProxyRegistry::default();
+    let mut routes = server::create_routes(&config.server, &config.rules, &registry)?;
+
+    for rule in listener_rules(config, listener)? {
+        let router = routes.take(rule)?;
+
+        let mut req = request(method, url, headers).map_err(|e| Issue::new(e, None))?;
+        let mut path_state = PathState::new(req.uri().path());
+        if router.detect(&mut req, &mut path_state).is_none() {
+            continue;
+        }
+
+        let req = request(method, url, headers).map_err(|e| Issue::new(e, None))?;
+        let res = Service::new(router).handle(req).await;
+        let header = |name| {
+            res.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+        };
+
+        return Ok(Some(Routed {
+            rule: rule.name.clone(),
+            action: rule.action.clone(),
+            status: res.status_code().unwrap_or(StatusCode::OK),
+            location: header(http::header::LOCATION.as_str()),
+            proxy_target: header(DRY_RUN_PROXY_TARGET),
+        }));
+    }
+
+    Ok(None)
+}
+
+async fn route(
+    filename: &str,
+    listener: Option<&str>,
+    method: &str,
+    url: &str,
+    headers: &[String],
+) -> i32 {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::ERROR)
+        .init();
+
+    let routed = match load(filename) {
+        Ok(config) => route_request(&config, listener, method, url, headers).await,
+        Err(report) => Err(report),
+    };
+
+    match routed {
+        Ok(Some(routed)) => {
+            println!("rule: {}", routed.rule);
+            println!("action: {}", routed.action);
+            if let Some(location) = routed.location {
+                println!("status: {}", routed.status.as_u16());
+                println!("location: {}", location);
+            }
+            if let Some(target) = routed.proxy_target {
+                println!("proxy target: {}", target);
+            }
+            0
+        }
+        Ok(None) => {
+            println!("no rule matches {} {}", method, url);
+            1
+        }
+        Err(report) => {
+            println!("{}: the configuration is invalid", filename);
+            println!("{}", report);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use salvo::http::StatusCode;
+
+    #[test]
+    fn test_validate() {
+        assert!(super::validate("tests/configs/004_redirect_action.yaml").is_ok());
+
+        let report = super::validate("tests/configs/007_invalid_rules.yaml").unwrap_err();
+        assert_eq!(report.issues.len(), 26);
+
+        let report = super::validate("tests/configs/not_found.yaml").unwrap_err();
+        assert_eq!(report.issues.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_route_request() {
+        let config =
+            Config::create_from_filename("tests/configs/004_redirect_action.yaml").unwrap();
+
+        let routed = super::route_request(
+            &config,
+            None,
+            "POST",
+            "http://127.0.0.1:5800/test2/42/b/hello",
+            &[],
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(routed.rule, "simple params");
+        assert_eq!(routed.status, StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(routed.location.unwrap(), "test242bhello");
+
+        let routed = super::route_request(&config, None, "GET", "http://127.0.0.1:5800/nope", &[])
+            .await
+            .unwrap();
+        assert!(routed.is_none());
+
+        let config =
+            Config::create_from_filename("tests/configs/003_filter_header_condition.yaml").unwrap();
+        let routed = super::route_request(
+            &config,
+            None,
+            "GET",
+            "http://127.0.0.1:5800/whatever",
+            &["bar: abc".to_string(), "foo: 1".to_string()],
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(routed.rule, "multiple headers");
+
+        let config = Config::create_from_filename("tests/configs/010_listeners.yaml").unwrap();
+        let url = "http://127.0.0.1:5800/admin";
+        let routed = super::route_request(&config, Some("127.0.0.1:5911"), "GET", url, &[])
+            .await
+            .unwrap();
+        assert!(routed.is_none());
+
+        let routed = super::route_request(&config, Some("127.0.0.1:5912"), "GET", url, &[])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(routed.rule, "admin");
+
+        assert!(
+            super::route_request(&config, Some("127.0.0.1:1"), "GET", url, &[])
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_request_proxy() {
+        // Nothing listens on the upstream: the request must not be forwarded.
+        let config = Config::create_from_filename("tests/configs/005_proxy_pool.yaml").unwrap();
+
+        let routed = super::route_request(
+            &config,
+            None,
+            "GET",
+            "http://127.0.0.1:5800/unpooled/a/b?c=d",
+            &[],
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(routed.rule, "proxy without idle connections");
+        assert_eq!(routed.action, "proxy");
+        assert_eq!(
+            routed.proxy_target.unwrap(),
+            "http://127.0.0.1:5902/unpooled/a/b?c=d"
+        );
+    }
+}
@@ -2,40 +2,542 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use http::Method;
+use regex::Regex;
 use salvo::prelude::Request;
-use salvo::routing::{Filter, PathState};
+use salvo::routing::{Filter, PathFilter, PathState};
 use std::fmt::{self, Formatter};
 
+// Matches when the method of the request is one of `methods`, or is not when
+// negated. With `implicit_head`, HEAD requests are served like GET ones.
+pub struct ConditionMethod {
+    methods: Vec<Method>,
+    negate: bool,
+    implicit_head: bool,
+}
+
+impl ConditionMethod {
+    pub fn new(methods: Vec<Method>, negate: bool, implicit_head: bool) -> ConditionMethod {
+        ConditionMethod {
+            methods,
+            negate,
+            implicit_head,
+        }
+    }
+}
+
+impl Filter for ConditionMethod {
+    fn filter(&self, req: &mut Request, _state: &mut PathState) -> bool {
+        let method = req.method();
+        let matched = self.methods.contains(method)
+            || (self.implicit_head
+                && method == Method::HEAD
+                && self.methods.contains(&Method::GET));
+        matched != self.negate
+    }
+}
+
+impl fmt::Debug for ConditionMethod {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "method {:?} - negate {} - implicit head {}",
+            self.methods, self.negate, self.implicit_head
+        )
+    }
+}
+
+// How a header or query value is compared with the one of the rule.
+pub enum ValueMatch {
+    Exact(String),
+    IgnoreCase(String),
+    Prefix(String),
+    Contains(String),
+    Regex(Regex),
+}
+
+impl ValueMatch {
+    pub fn new(mode: Option<&str>, value: &str) -> Result<ValueMatch, String> {
+        match mode.unwrap_or("exact") {
+            "exact" => Ok(ValueMatch::Exact(value.to_string())),
+            "ignore_case" => Ok(ValueMatch::IgnoreCase(value.to_lowercase())),
+            "prefix" => Ok(ValueMatch::Prefix(value.to_string())),
+            "contains" => Ok(ValueMatch::Contains(value.to_string())),
+            "regex" => Regex::new(value)
+                .map(ValueMatch::Regex)
+                .map_err(|e| format!("invalid regex `{}`: {}", value, e)),
+            mode => Err(format!("invalid match mode `{}`", mode)),
+        }
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            ValueMatch::Exact(v) => value == v,
+            ValueMatch::IgnoreCase(v) => value.to_lowercase() == *v,
+            ValueMatch::Prefix(v) => value.starts_with(v.as_str()),
+            ValueMatch::Contains(v) => value.contains(v.as_str()),
+            ValueMatch::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+impl fmt::Debug for ValueMatch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ValueMatch::Exact(v) => write!(f, "exact {:?}", v),
+            ValueMatch::IgnoreCase(v) => write!(f, "ignore_case {:?}", v),
+            ValueMatch::Prefix(v) => write!(f, "prefix {:?}", v),
+            ValueMatch::Contains(v) => write!(f, "contains {:?}", v),
+            ValueMatch::Regex(regex) => write!(f, "regex {:?}", regex.as_str()),
+        }
+    }
+}
+
+// Matches when one of the values of the header matches, or when the header is
+// present if no value is given. `negate` inverts the result: the header must
+// be absent, or none of its values must match.
 pub struct ConditionHeader {
     name: String,
-    value: Option<String>,
+    value: Option<ValueMatch>,
+    negate: bool,
 }
 
 impl ConditionHeader {
-    pub fn new(name: &str, value: Option<&str>) -> ConditionHeader {
-        tracing::info!(target: "ConditionHeader", name=name, value=value, "condition header created");
+    pub fn new(name: &str, value: Option<ValueMatch>, negate: bool) -> ConditionHeader {
+        tracing::info!(target: "ConditionHeader", name=name, value=?value, negate=negate, "condition header created");
         ConditionHeader {
             name: name.to_string(),
-            value: value.map(|s| s.into()),
+            value,
+            negate,
         }
     }
 }
 
 impl Filter for ConditionHeader {
     fn filter(&self, req: &mut Request, _state: &mut PathState) -> bool {
-        let header_value: Option<String> = req.header(&self.name);
-        match header_value.as_ref() {
-            None => false,
-            Some(value) => match self.value.as_ref() {
-                None => true,
-                Some(v) => value == v,
-            },
-        }
+        let mut values = req.headers().get_all(&self.name).iter();
+        let matched = match self.value.as_ref() {
+            None => values.next().is_some(),
+            Some(value) => values.any(|v| v.to_str().is_ok_and(|v| value.matches(v))),
+        };
+        matched != self.negate
     }
 }
 
 impl fmt::Debug for ConditionHeader {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "header {} - value {:?}", self.name, self.value)
+        write!(
+            f,
+            "header {} - value {:?} - negate {}",
+            self.name, self.value, self.negate
+        )
+    }
+}
+
+// The decoded values of the query parameter `name`.
+pub fn query_values(req: &Request, name: &str) -> Vec<String> {
+    url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .filter(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+        .collect()
+}
+
+// Like `ConditionHeader`, for the query parameters.
+pub struct ConditionQuery {
+    name: String,
+    value: Option<ValueMatch>,
+    negate: bool,
+}
+
+impl ConditionQuery {
+    pub fn new(name: &str, value: Option<ValueMatch>, negate: bool) -> ConditionQuery {
+        tracing::info!(target: "ConditionQuery", name=name, value=?value, negate=negate, "condition query created");
+        ConditionQuery {
+            name: name.to_string(),
+            value,
+            negate,
+        }
+    }
+}
+
+impl Filter for ConditionQuery {
+    fn filter(&self, req: &mut Request, _state: &mut PathState) -> bool {
+        let values = query_values(req, &self.name);
+        let matched = match self.value.as_ref() {
+            None => !values.is_empty(),
+            Some(value) => values.iter().any(|v| value.matches(v)),
+        };
+        matched != self.negate
+    }
+}
+
+impl fmt::Debug for ConditionQuery {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "query {} - value {:?} - negate {}",
+            self.name, self.value, self.negate
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum HostLabel {
+    Exact(String),
+    // `<name>`: any label, captured as a parameter.
+    Param(String),
+    // `*`: one or more labels, only as the leftmost label.
+    Wildcard,
+}
+
+// A host name pattern such as `example.com`, `*.example.com` or
+// `<user>.example.com`.
+#[derive(Debug, Clone)]
+pub struct HostPattern {
+    pattern: String,
+    labels: Vec<HostLabel>,
+}
+
+impl HostPattern {
+    pub fn parse(pattern: &str) -> Result<HostPattern, String> {
+        let mut labels = Vec::new();
+
+        for (i, label) in pattern.to_lowercase().split('.').enumerate() {
+            let label = if label == "*" {
+                if i > 0 {
+                    return Err(format!(
+                        "invalid host `{}`: `*` is only allowed as the first label",
+                        pattern
+                    ));
+                }
+                HostLabel::Wildcard
+            } else if let Some(name) = label.strip_prefix('<').and_then(|l| l.strip_suffix('>')) {
+                if name.is_empty() {
+                    return Err(format!("invalid host `{}`: empty parameter name", pattern));
+                }
+                HostLabel::Param(name.to_string())
+            } else if label.is_empty() || label.contains(['<', '>', '*']) {
+                return Err(format!("invalid host `{}`", pattern));
+            } else {
+                HostLabel::Exact(label.to_string())
+            };
+            labels.push(label);
+        }
+
+        Ok(HostPattern {
+            pattern: pattern.to_string(),
+            labels,
+        })
+    }
+
+    // Returns the captured parameters if the host matches.
+    pub fn captures(&self, host: &str) -> Option<Vec<(String, String)>> {
+        let host = host.trim_end_matches('.').to_lowercase();
+        let host_labels: Vec<&str> = host.split('.').collect();
+
+        let mut captures = Vec::new();
+        let (labels, host_labels) = match self.labels.first() {
+            Some(HostLabel::Wildcard) => {
+                if host_labels.len() < self.labels.len() {
+                    return None;
+                }
+                let skip = host_labels.len() - self.labels.len() + 1;
+                (&self.labels[1..], &host_labels[skip..])
+            }
+            _ => (&self.labels[..], &host_labels[..]),
+        };
+
+        if labels.len() != host_labels.len() {
+            return None;
+        }
+
+        for (label, host_label) in labels.iter().zip(host_labels) {
+            match label {
+                HostLabel::Exact(exact) if exact == host_label => {}
+                HostLabel::Param(name) if !host_label.is_empty() => {
+                    captures.push((name.clone(), host_label.to_string()))
+                }
+                _ => return None,
+            }
+        }
+
+        Some(captures)
+    }
+
+    // Returns the captured parameters if the host of the request matches.
+    pub fn request_captures(&self, req: &Request) -> Option<Vec<(String, String)>> {
+        self.captures(&request_host(req)?)
+    }
+}
+
+// The host the request is for, without the port. HTTP/2 requests carry it in
+// the URI instead of the `Host` header.
+pub fn request_host(req: &Request) -> Option<String> {
+    let host: Option<String> = req.header(http::header::HOST);
+    let host = host.or_else(|| req.uri().host().map(String::from))?;
+
+    // `[::1]` is an IPv6 address without port.
+    if !host.ends_with(']') {
+        if let Some((name, port)) = host.rsplit_once(':') {
+            if port.chars().all(|c| c.is_ascii_digit()) {
+                return Some(name.to_string());
+            }
+        }
+    }
+
+    Some(host)
+}
+
+pub struct ConditionHost {
+    pattern: HostPattern,
+}
+
+impl ConditionHost {
+    pub fn new(pattern: HostPattern) -> ConditionHost {
+        tracing::info!(target: "ConditionHost", host=pattern.pattern, "condition host created");
+        ConditionHost { pattern }
+    }
+}
+
+impl Filter for ConditionHost {
+    fn filter(&self, req: &mut Request, _state: &mut PathState) -> bool {
+        self.pattern.request_captures(req).is_some()
+    }
+}
+
+impl fmt::Debug for ConditionHost {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "host {}", self.pattern.pattern)
+    }
+}
+
+// Splits `value` on `separator`, ignoring the separators in quoted strings.
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+
+    for (i, c) in value.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&value[start..i]);
+            start = i + 1;
+        }
+    }
+
+    parts.push(&value[start..]);
+    parts
+}
+
+// A media type, or a media range of the `Accept` header when it contains
+// wildcards.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaType {
+    main: String,
+    sub: String,
+    params: Vec<(String, String)>,
+}
+
+impl MediaType {
+    // Parses a media type with its parameters, returning the `q` parameter
+    // apart (1 when missing).
+    pub fn parse(value: &str) -> Result<(MediaType, f32), String> {
+        let mut parts = split_unquoted(value, ';').into_iter();
+        let essence = parts.next().unwrap_or_default().trim().to_lowercase();
+
+        let (main, sub) = match essence.split_once('/') {
+            Some((main, sub)) if !main.is_empty() && !sub.is_empty() => (main, sub),
+            _ => return Err(format!("invalid media type `{}`", value.trim())),
+        };
+
+        let mut media_type = MediaType {
+            main: main.to_string(),
+            sub: sub.to_string(),
+            params: Vec::new(),
+        };
+        let mut q = 1.0;
+
+        for param in parts {
+            let (name, param_value) = match param.split_once('=') {
+                Some((name, param_value)) => (name.trim().to_lowercase(), param_value.trim()),
+                None => return Err(format!("invalid media type `{}`", value.trim())),
+            };
+            let param_value = param_value.trim_matches('"').to_string();
+
+            if name == "q" {
+                q = param_value
+                    .parse::<f32>()
+                    .map_err(|_| format!("invalid q-value in `{}`", value.trim()))?
+                    .clamp(0.0, 1.0);
+            } else {
+                media_type.params.push((name, param_value));
+            }
+        }
+
+        Ok((media_type, q))
+    }
+
+    fn is_wildcard(&self) -> bool {
+        self.main == "*" || self.sub == "*"
+    }
+
+    // How precisely this media range matches `media_type`, if it does.
+    fn specificity(&self, media_type: &MediaType) -> Option<u8> {
+        if self.main == "*" {
+            return (self.sub == "*").then_some(0);
+        }
+        if self.main != media_type.main {
+            return None;
+        }
+        if self.sub == "*" {
+            return Some(1);
+        }
+        if self.sub != media_type.sub {
+            return None;
+        }
+        if self.params.is_empty() {
+            return Some(2);
+        }
+        self.params
+            .iter()
+            .all(|param| media_type.params.contains(param))
+            .then_some(3)
+    }
+}
+
+// The media ranges of an `Accept` header with their q-values. Invalid ranges
+// are ignored, and a missing header accepts everything.
+fn accepted_ranges(req: &Request) -> Vec<(MediaType, f32)> {
+    let values: Vec<&str> = req
+        .headers()
+        .get_all(http::header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+
+    if values.is_empty() {
+        return vec![(MediaType::parse("*/*").unwrap().0, 1.0)];
+    }
+
+    values
+        .into_iter()
+        .flat_map(|value| split_unquoted(value, ','))
+        .filter(|range| !range.trim().is_empty())
+        .filter_map(|range| MediaType::parse(range).ok())
+        .collect()
+}
+
+// The most specific range matching `media_type`, as its specificity and the
+// q-value it gives.
+fn quality(ranges: &[(MediaType, f32)], media_type: &MediaType) -> Option<(u8, f32)> {
+    ranges
+        .iter()
+        .filter_map(|(range, q)| range.specificity(media_type).map(|s| (s, *q)))
+        .max_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)))
+}
+
+// The media type the client prefers among `types`, if it accepts any. Ties go
+// to the first one.
+pub fn preferred_media_type<'a>(req: &Request, types: &[&'a str]) -> Option<&'a str> {
+    let ranges = accepted_ranges(req);
+    let mut preferred: Option<(&str, f32)> = None;
+
+    for value in types {
+        let media_type = match MediaType::parse(value) {
+            Ok((media_type, _)) => media_type,
+            Err(_) => continue,
+        };
+        if let Some((_, q)) = quality(&ranges, &media_type) {
+            if q > 0.0 && preferred.is_none_or(|(_, best)| q > best) {
+                preferred = Some((value, q));
+            }
+        }
+    }
+
+    preferred.map(|(value, _)| value)
+}
+
+// Matches when the client accepts one of the media types. With `preferred`,
+// one of them must also have the highest q-value of the `Accept` header, and
+// be matched by more than `*/*`: a client accepting anything has no
+// preference.
+pub struct ConditionAccept {
+    types: Vec<MediaType>,
+    preferred: bool,
+}
+
+impl ConditionAccept {
+    pub fn new(types: &[String], preferred: bool) -> Result<ConditionAccept, String> {
+        let mut media_types = Vec::new();
+        for value in types {
+            let (media_type, _) = MediaType::parse(value)?;
+            if media_type.is_wildcard() {
+                return Err(format!("`{}` is not a media type", value));
+            }
+            media_types.push(media_type);
+        }
+
+        if media_types.is_empty() {
+            return Err("no media types".to_string());
+        }
+
+        tracing::info!(target: "ConditionAccept", types=?types, preferred=preferred, "condition accept created");
+        Ok(ConditionAccept {
+            types: media_types,
+            preferred,
+        })
+    }
+}
+
+impl Filter for ConditionAccept {
+    fn filter(&self, req: &mut Request, _state: &mut PathState) -> bool {
+        let ranges = accepted_ranges(req);
+        let qualities: Vec<(u8, f32)> = self
+            .types
+            .iter()
+            .filter_map(|media_type| quality(&ranges, media_type))
+            .filter(|(_, q)| *q > 0.0)
+            .collect();
+
+        if !self.preferred {
+            return !qualities.is_empty();
+        }
+
+        let highest = ranges.iter().map(|(_, q)| *q).fold(0.0, f32::max);
+        qualities
+            .iter()
+            .any(|(specificity, q)| *specificity > 0 && *q >= highest)
+    }
+}
+
+impl fmt::Debug for ConditionAccept {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "accept {:?} - preferred {}", self.types, self.preferred)
+    }
+}
+
+// The conditions of a `match` block.
+#[derive(Debug)]
+pub enum ConditionMatch {
+    All(Vec<Box<dyn Filter>>),
+    Any(Vec<Box<dyn Filter>>),
+    Not(Box<dyn Filter>),
+    // Matches the whole path of the request. The path state of the router is
+    // left untouched, as conditions can be evaluated in any combination.
+    Path(PathFilter),
+}
+
+impl Filter for ConditionMatch {
+    fn filter(&self, req: &mut Request, state: &mut PathState) -> bool {
+        match self {
+            ConditionMatch::All(filters) => filters.iter().all(|f| f.filter(req, state)),
+            ConditionMatch::Any(filters) => filters.iter().any(|f| f.filter(req, state)),
+            ConditionMatch::Not(filter) => !filter.filter(req, state),
+            ConditionMatch::Path(filter) => {
+                let mut path_state = PathState::new(req.uri().path());
+                filter.filter(req, &mut path_state) && path_state.ended()
+            }
+        }
     }
 }
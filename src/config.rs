@@ -2,65 +2,393 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::errors::Error;
+use crate::validation::{Issue, Location, Report, RuleLocations};
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
     pub server: ConfigServer,
     pub rules: Vec<ConfigRule>,
+
+    // The file this configuration has been loaded from. Used to reload it.
+    #[serde(skip)]
+    pub filename: String,
 }
 
-#[derive(Deserialize, Debug)]
+// `bind` and `tls` configure a listener serving every rule. More listeners
+// can be added with `listeners`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct ConfigServer {
+    pub bind: Option<String>,
+    pub tls: Option<ConfigTls>,
+    pub http3: Option<ConfigHttp3>,
+    pub listeners: Option<Vec<ConfigListener>>,
+    // Rules serving GET requests also serve HEAD requests.
+    pub implicit_head: Option<bool>,
+    // The default timeouts of the proxy rules.
+    pub timeouts: Option<ConfigTimeouts>,
+}
+
+// A listener serves the rules whose name or tags are in `rules`, or every rule
+// when `rules` is not set.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ConfigListener {
     pub bind: String,
+    pub tls: Option<ConfigTls>,
+    pub http3: Option<ConfigHttp3>,
+    pub rules: Option<Vec<String>>,
+}
+
+// HTTP/3 served over QUIC next to a TLS listener, with the same certificates
+// and rules. `bind` is the UDP address, the one of the listener by default.
+// The TCP responses advertise it with `Alt-Svc`, for `max_age` seconds (one
+// day by default).
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ConfigHttp3 {
+    pub bind: Option<String>,
+    pub max_age: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ConfigTls {
+    pub certificates: Vec<ConfigTlsCertificate>,
+}
+
+// A PEM certificate chain and its private key, selected when the client asks
+// for one of `server_names` via SNI (`*.example.com` wildcards are supported).
+// The first certificate is the default one, used when no name matches.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ConfigTlsCertificate {
+    pub cert: String,
+    pub key: String,
+    pub server_names: Option<Vec<String>>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct ConfigRule {
     pub name: String,
-    pub method: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub method: Option<ConfigRuleMethod>,
+    pub host: Option<String>,
     pub path: Option<String>,
     pub headers: Option<Vec<ConfigRuleHeader>>,
+    pub query: Option<Vec<ConfigRuleQuery>>,
+    pub accept: Option<ConfigRuleAccept>,
+    #[serde(rename = "match")]
+    pub matches: Option<ConfigMatch>,
     pub action: String,
     pub redirect_to: Option<String>,
     pub redirect_status: Option<u16>,
-    pub proxy_url: Option<String>,
+    pub proxy_url: Option<ConfigProxyUrl>,
+    pub balance: Option<ConfigRuleBalance>,
+    pub health_check: Option<ConfigRuleHealthCheck>,
+    pub retry: Option<ConfigRuleRetry>,
+    pub timeouts: Option<ConfigTimeouts>,
+    pub forwarded_headers: Option<ConfigForwardedHeaders>,
+    // The Host header sent to the upstream: `preserve` (the default) keeps the
+    // one of the client, `upstream` uses the upstream address, and any other
+    // value replaces it.
+    pub proxy_host: Option<String>,
+    pub proxy_path: Option<ConfigProxyPath>,
+    pub proxy_tls: Option<ConfigProxyTls>,
+    // `http1` (the default), `h2` or `h2c`.
+    pub upstream_protocol: Option<String>,
+    pub pool: Option<ConfigRulePool>,
+    pub upgrade: Option<ConfigRuleUpgrade>,
+
+    #[serde(skip)]
+    pub locations: RuleLocations,
 }
 
+// A method (`GET`), a list of methods (`[GET, POST]`), or the methods a rule
+// does not serve (`not: [POST]`).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ConfigRuleMethod {
+    One(String),
+    List(Vec<String>),
+    Not { not: Vec<String> },
+}
+
+// `match` is one of `exact` (the default), `ignore_case`, `prefix`, `contains`
+// or `regex`. With `negate`, the header must be absent or not match.
 #[derive(Deserialize, Debug, Clone)]
 pub struct ConfigRuleHeader {
     pub name: String,
     pub value: Option<String>,
+    #[serde(rename = "match")]
+    pub match_mode: Option<String>,
+    pub negate: Option<bool>,
 }
 
-impl Config {
-    pub fn create() -> Config {
-        let filename = std::env::args().nth(1);
+// A query parameter, matched like the headers. The first value of the
+// parameter can be used as `<name>` in `redirect_to`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConfigRuleQuery {
+    pub name: String,
+    pub value: Option<String>,
+    #[serde(rename = "match")]
+    pub match_mode: Option<String>,
+    pub negate: Option<bool>,
+}
+
+// Content negotiation: the rule matches when the client accepts one of
+// `types`, or prefers it if `preferred` is set.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConfigRuleAccept {
+    pub types: Vec<String>,
+    pub preferred: Option<bool>,
+}
 
-        if filename.is_none() {
-            tracing::error!(target: "Config", "no configuration file");
-            std::process::exit(1);
+// A block of conditions, all of which must match. `all`, `any` and `not`
+// combine nested blocks. Parameters of paths in a block are not captured.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConfigMatch {
+    pub all: Option<Vec<ConfigMatch>>,
+    pub any: Option<Vec<ConfigMatch>>,
+    pub not: Option<Box<ConfigMatch>>,
+    pub method: Option<ConfigRuleMethod>,
+    pub host: Option<String>,
+    pub path: Option<String>,
+    pub header: Option<ConfigRuleHeader>,
+    pub query: Option<ConfigRuleQuery>,
+    pub accept: Option<ConfigRuleAccept>,
+}
+
+// One upstream (`localhost:3000`), or a list of upstreams with optional
+// weights.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ConfigProxyUrl {
+    One(String),
+    List(Vec<ConfigUpstream>),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ConfigUpstream {
+    Url(String),
+    Weighted { url: String, weight: Option<u32> },
+}
+
+// How requests are distributed between the upstreams. `strategy` is one of
+// `round_robin` (the default), `least_connections`, `random` or `hash`. The
+// `hash` strategy sends the requests with the same `header` value, or from
+// the same client IP when `header` is not set, to the same upstream.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ConfigRuleBalance {
+    pub strategy: Option<String>,
+    pub header: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ConfigRuleHealthCheck {
+    pub active: Option<ConfigActiveHealthCheck>,
+    pub passive: Option<ConfigPassiveHealthCheck>,
+}
+
+// Each upstream receives a GET request for `path` every `interval` seconds
+// (10 by default). It is unhealthy after `unhealthy_threshold` (3) failed
+// probes, and healthy again after `healthy_threshold` (2) successful ones. A
+// probe fails if the status is not `status` (200) or after `timeout` seconds
+// (2).
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConfigActiveHealthCheck {
+    pub path: String,
+    pub status: Option<u16>,
+    pub interval: Option<u64>,
+    pub timeout: Option<u64>,
+    pub healthy_threshold: Option<u32>,
+    pub unhealthy_threshold: Option<u32>,
+}
+
+// An upstream is ejected for `ejection_time` seconds (30 by default) after
+// `max_failures` (3) consecutive connection failures.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConfigPassiveHealthCheck {
+    pub max_failures: Option<u32>,
+    pub ejection_time: Option<u64>,
+}
+
+// Failed requests are sent again, to another upstream when possible, up to
+// `attempts` more times (2 by default), waiting `backoff` milliseconds (100)
+// doubled at each retry. Only the `methods` (the idempotent ones by default)
+// are retried, after a connection error or one of the `statuses` (502, 503
+// and 504). Request bodies up to `max_body_size` bytes (64 KiB) are buffered to
+// be replayed: larger ones are never retried.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ConfigRuleRetry {
+    pub attempts: Option<u32>,
+    pub backoff: Option<u64>,
+    pub methods: Option<Vec<String>>,
+    pub statuses: Option<Vec<u16>>,
+    pub max_body_size: Option<usize>,
+}
+
+// Requests switching protocols (WebSockets) are tunneled to the upstream when
+// `allow` is set (it is not by default); otherwise, the `Upgrade` header is
+// dropped. A tunnel is closed after `idle_timeout` seconds (300) without data
+// in either direction.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ConfigRuleUpgrade {
+    pub allow: Option<bool>,
+    pub idle_timeout: Option<f64>,
+}
+
+// Timeouts in seconds, with fractions. `connect` limits the TCP connection to
+// an upstream, `first_byte` the wait for its response headers, and
+// `idle_body` the wait for each chunk of its response body. `total` limits the
+// whole request, retries and response body included. The timeouts of a rule
+// default to the ones of the `server` section; there is none otherwise.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ConfigTimeouts {
+    pub connect: Option<f64>,
+    pub first_byte: Option<f64>,
+    pub total: Option<f64>,
+    pub idle_body: Option<f64>,
+}
+
+// The headers describing the client sent to the upstreams. `X-Forwarded-For`,
+// `X-Forwarded-Proto`, `X-Forwarded-Host` and `X-Real-IP` are sent by default,
+// the RFC 7239 `Forwarded` header is not. The values sent by the client are
+// replaced, unless `trust_incoming` is set: the client IP is then appended to
+// them.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ConfigForwardedHeaders {
+    pub x_forwarded_for: Option<bool>,
+    pub x_forwarded_proto: Option<bool>,
+    pub x_forwarded_host: Option<bool>,
+    pub x_real_ip: Option<bool>,
+    pub forwarded: Option<bool>,
+    pub trust_incoming: Option<bool>,
+}
+
+// The path sent to the upstream: `strip_prefix` and `add_prefix` replace its
+// prefix, or `rewrite` replaces it with a template using the parameters of the
+// rule, such as `/<rest>` for the path `elk/<**rest>`.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ConfigProxyPath {
+    pub strip_prefix: Option<String>,
+    pub add_prefix: Option<String>,
+    pub rewrite: Option<String>,
+}
+
+// The TLS settings of the `https` upstreams: `ca`, a PEM bundle of the
+// certificate authorities to trust instead of the usual ones, `server_name` to
+// send and verify instead of the upstream host, and a client certificate with
+// `cert` and `key`.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ConfigProxyTls {
+    pub ca: Option<String>,
+    pub server_name: Option<String>,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ConfigRulePool {
+    pub max_idle: Option<usize>,
+    pub idle_timeout: Option<u64>,
+}
+
+impl ConfigServer {
+    pub fn listeners(&self) -> Vec<ConfigListener> {
+        let mut listeners = Vec::new();
+
+        if let Some(bind) = &self.bind {
+            listeners.push(ConfigListener {
+                bind: bind.clone(),
+                tls: self.tls.clone(),
+                http3: self.http3.clone(),
+                rules: None,
+            });
         }
 
-        Config::create_from_filename(&filename.unwrap())
+        listeners.extend(self.listeners.iter().flatten().cloned());
+        listeners
     }
+}
 
-    pub fn create_from_filename(filename: &str) -> Config {
-        let content = match std::fs::read_to_string(&filename) {
-            Ok(c) => c,
-            Err(_) => {
-                tracing::error!(target: "Config", filename=filename, "could not read file");
+impl ConfigListener {
+    pub fn selects(&self, rule: &ConfigRule) -> bool {
+        match &self.rules {
+            None => true,
+            Some(selectors) => selectors
+                .iter()
+                .any(|selector| rule.is_selected_by(selector)),
+        }
+    }
+}
+
+impl ConfigProxyUrl {
+    // The upstream URLs with their weights, 1 by default.
+    pub fn upstreams(&self) -> Vec<(&str, u32)> {
+        match self {
+            ConfigProxyUrl::One(url) => vec![(url.as_str(), 1)],
+            ConfigProxyUrl::List(upstreams) => upstreams
+                .iter()
+                .map(|upstream| match upstream {
+                    ConfigUpstream::Url(url) => (url.as_str(), 1),
+                    ConfigUpstream::Weighted { url, weight } => (url.as_str(), weight.unwrap_or(1)),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl ConfigRule {
+    // Rules are selected by their name or by one of their tags.
+    pub fn is_selected_by(&self, selector: &str) -> bool {
+        self.name == selector || self.tags.iter().flatten().any(|tag| tag == selector)
+    }
+}
+
+impl Config {
+    pub fn create(filename: &str) -> Config {
+        match Config::create_from_filename(filename) {
+            Ok(config) => config,
+            Err(Error::InvalidConfig(report)) => {
+                report.log(filename);
+                std::process::exit(1);
+            }
+            Err(e) => {
+                tracing::error!(target: "Config", filename=filename, error = e.to_string(), "unable to load the configuration");
                 std::process::exit(1);
             }
+        }
+    }
+
+    pub fn create_from_filename(filename: &str) -> Result<Config, Error> {
+        let content = match std::fs::read_to_string(filename) {
+            Ok(c) => c,
+            Err(e) => {
+                return Err(
+                    Report::from(Issue::new(format!("could not read file: {}", e), None)).into(),
+                )
+            }
         };
-        let config: Config = match serde_yaml::from_str(&content) {
+
+        let mut config: Config = match serde_yaml::from_str(&content) {
             Ok(config) => config,
             Err(e) => {
-                tracing::error!(target: "Config", filename=filename, error = e.to_string(), "unable to load data");
-                std::process::exit(1);
+                let location = e.location().map(|l| Location {
+                    line: l.line(),
+                    column: l.column(),
+                });
+                return Err(Report::from(Issue::new(e.to_string(), location)).into());
             }
         };
 
-        config
+        for (rule, locations) in config
+            .rules
+            .iter_mut()
+            .zip(crate::validation::rule_locations(&content))
+        {
+            rule.locations = locations;
+        }
+
+        config.filename = filename.to_string();
+        Ok(config)
     }
 }
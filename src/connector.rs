@@ -0,0 +1,415 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::config::ConfigProxyTls;
+use crate::tls::{load_certs, load_private_key};
+use hyper::client::connect::{Connected, Connection};
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::Uri;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// The protocol spoken with the upstreams: HTTP/1.1 (`http1`), HTTP/2 when
+// negotiated with ALPN by the `https` ones (`h2`), or HTTP/2 with prior
+// knowledge (`h2c`). HTTP/2 connections are shared by concurrent requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum UpstreamProtocol {
+    #[default]
+    Http1,
+    H2,
+    H2c,
+}
+
+impl UpstreamProtocol {
+    pub fn parse(value: &str) -> Result<UpstreamProtocol, String> {
+        match value {
+            "http1" => Ok(UpstreamProtocol::Http1),
+            "h2" => Ok(UpstreamProtocol::H2),
+            "h2c" => Ok(UpstreamProtocol::H2c),
+            _ => Err(format!("invalid protocol `{}`", value)),
+        }
+    }
+
+    fn alpn_protocols(&self) -> Vec<Vec<u8>> {
+        match self {
+            UpstreamProtocol::Http1 => vec![b"http/1.1".to_vec()],
+            UpstreamProtocol::H2 => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            UpstreamProtocol::H2c => vec![b"h2".to_vec()],
+        }
+    }
+}
+
+// The TLS settings of the connections to `https` upstreams. The server name
+// sent with SNI, and verified, is the host of the upstream unless set.
+#[derive(Clone)]
+pub struct UpstreamTls {
+    connector: TlsConnector,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl UpstreamTls {
+    pub fn new(config: &ConfigProxyTls, protocol: UpstreamProtocol) -> Result<UpstreamTls, String> {
+        let mut roots = RootCertStore::empty();
+        match &config.ca {
+            Some(ca) => {
+                for cert in load_certs(ca)? {
+                    roots
+                        .add(cert)
+                        .map_err(|e| format!("invalid certificate `{}`: {}", ca, e))?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_root_certificates(roots);
+
+        let mut client_config = match (&config.cert, &config.key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(load_certs(cert)?, load_private_key(key)?)
+                .map_err(|e| format!("`{}` and `{}`: {}", cert, key, e))?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err("`cert` and `key` must be set together".to_string()),
+        };
+        client_config.alpn_protocols = protocol.alpn_protocols();
+
+        let server_name = match &config.server_name {
+            Some(name) => Some(
+                ServerName::try_from(name.clone())
+                    .map_err(|_| format!("invalid server name `{}`", name))?,
+            ),
+            None => None,
+        };
+
+        Ok(UpstreamTls {
+            connector: TlsConnector::from(Arc::new(client_config)),
+            server_name,
+        })
+    }
+}
+
+// The URIs of the Unix socket upstreams have the `unix` scheme, and the path
+// of the socket hex encoded as authority.
+pub const UNIX_SCHEME: &str = "unix";
+
+pub fn unix_authority(path: &str) -> String {
+    path.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unix_path(authority: &str) -> Option<String> {
+    let bytes = (0..authority.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(authority.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+// Connects to the upstreams over TCP, with TLS for the `https` ones, or over
+// Unix sockets.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    http: HttpConnector,
+    tls: Option<UpstreamTls>,
+    connect_timeout: Option<Duration>,
+}
+
+impl UpstreamConnector {
+    pub fn new(connect_timeout: Option<Duration>, tls: Option<UpstreamTls>) -> UpstreamConnector {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        http.set_connect_timeout(connect_timeout);
+        UpstreamConnector {
+            http,
+            tls,
+            connect_timeout,
+        }
+    }
+
+    fn connect_unix(&self, uri: &Uri) -> <Self as Service<Uri>>::Future {
+        let path = uri
+            .authority()
+            .and_then(|authority| unix_path(authority.as_str()));
+        let connect_timeout = self.connect_timeout;
+
+        Box::pin(async move {
+            let path = path.ok_or("invalid Unix socket URI")?;
+            let connecting = UnixStream::connect(path);
+            let stream = match connect_timeout {
+                Some(connect_timeout) => tokio::time::timeout(connect_timeout, connecting)
+                    .await
+                    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??,
+                None => connecting.await?,
+            };
+            Ok(UpstreamStream::Unix(stream))
+        })
+    }
+}
+
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Unix(UnixStream),
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = UpstreamStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<UpstreamStream, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        if uri.scheme_str() == Some(UNIX_SCHEME) {
+            return self.connect_unix(&uri);
+        }
+
+        let tls = match uri.scheme_str() {
+            Some("https") => match &self.tls {
+                Some(tls) => Some(tls.clone()),
+                None => {
+                    return Box::pin(async { Err("no TLS settings for an https upstream".into()) })
+                }
+            },
+            _ => None,
+        };
+        let host = uri.host().unwrap_or_default().to_string();
+        let connecting = self.http.call(uri);
+
+        Box::pin(async move {
+            let stream = connecting.await?;
+            let tls = match tls {
+                Some(tls) => tls,
+                None => return Ok(UpstreamStream::Plain(stream)),
+            };
+
+            let server_name = match tls.server_name {
+                Some(server_name) => server_name,
+                // IPv6 addresses are in brackets in URIs.
+                None => ServerName::try_from(host.trim_matches(|c| c == '[' || c == ']'))
+                    .map_err(|_| format!("invalid server name `{}`", host))?
+                    .to_owned(),
+            };
+            let stream = tls.connector.connect(server_name, stream).await?;
+            Ok(UpstreamStream::Tls(Box::new(stream)))
+        })
+    }
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        match self {
+            UpstreamStream::Plain(stream) => stream.connected(),
+            UpstreamStream::Tls(stream) => {
+                let (stream, session) = stream.get_ref();
+                match session.alpn_protocol() {
+                    Some(b"h2") => stream.connected().negotiated_h2(),
+                    _ => stream.connected(),
+                }
+            }
+            UpstreamStream::Unix(_) => Connected::new(),
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use crate::routers;
+    use crate::tls::{load_certs, load_private_key};
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use hyper::Body;
+    use salvo::http::StatusCode;
+    use salvo::prelude::Service;
+    use salvo::test::{ResponseExt, TestClient};
+    use std::convert::Infallible;
+    use std::sync::Arc;
+    use tokio::net::{TcpListener, UnixListener};
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::server::WebPkiClientVerifier;
+    use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+
+    // Starts an https upstream requiring a client certificate, echoing back
+    // the request path, with its HTTP version in `x-version`. It accepts
+    // HTTP/2 with ALPN.
+    async fn https_upstream(bind: &str) {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(load_certs("tests/certs/ca.pem").unwrap().remove(0))
+            .unwrap();
+        let provider = Arc::new(ring::default_provider());
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .unwrap();
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                load_certs("tests/certs/localhost.pem").unwrap(),
+                load_private_key("tests/certs/localhost.key").unwrap(),
+            )
+            .unwrap();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind(bind).await.unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        let handler = service_fn(|req: hyper::Request<Body>| async move {
+                            Ok::<_, Infallible>(
+                                hyper::Response::builder()
+                                    .header("x-version", format!("{:?}", req.version()))
+                                    .body(Body::from(
+                                        req.uri().path_and_query().unwrap().to_string(),
+                                    ))
+                                    .unwrap(),
+                            )
+                        });
+                        Http::new().serve_connection(stream, handler).await.ok();
+                    }
+                });
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn test_https_upstream() {
+        https_upstream("127.0.0.1:5919").await;
+
+        let config = Config::create_from_filename("tests/configs/022_https_upstream.yaml").unwrap();
+        let service = Service::new(routers::routers(&config.rules, &config.server).unwrap());
+
+        let mut resp = TestClient::get("http://127.0.0.1:5800/tls/path?a=1")
+            .send(&service)
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::OK);
+        assert_eq!(resp.headers()["x-version"], "HTTP/1.1");
+        assert_eq!(resp.take_string().await.unwrap(), "/base/tls/path?a=1");
+
+        let mut resp = TestClient::get("http://127.0.0.1:5800/h2/path")
+            .send(&service)
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::OK);
+        assert_eq!(resp.headers()["x-version"], "HTTP/2.0");
+        assert_eq!(resp.take_string().await.unwrap(), "/h2/path");
+
+        for url in [
+            "http://127.0.0.1:5800/untrusted/path",
+            "http://127.0.0.1:5800/anonymous/path",
+        ] {
+            let resp = TestClient::get(url).send(&service).await;
+            assert_eq!(resp.status_code().unwrap(), StatusCode::BAD_GATEWAY);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unix_upstream() {
+        let path = "/tmp/social-routing-test-upstream.sock";
+        std::fs::remove_file(path).ok();
+        let listener = UnixListener::bind(path).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let handler = service_fn(|req: hyper::Request<Body>| async move {
+                    Ok::<_, Infallible>(
+                        hyper::Response::builder()
+                            .header("x-host", req.headers()["host"].clone())
+                            .body(Body::from(req.uri().to_string()))
+                            .unwrap(),
+                    )
+                });
+                tokio::spawn(Http::new().serve_connection(stream, handler));
+            }
+        });
+
+        let config = Config::create_from_filename("tests/configs/023_unix_upstream.yaml").unwrap();
+        let service = Service::new(routers::routers(&config.rules, &config.server).unwrap());
+
+        let mut resp = TestClient::get("http://127.0.0.1:5800/unix/path?a=1")
+            .send(&service)
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::OK);
+        assert_eq!(resp.headers()["x-host"], "localhost");
+        assert_eq!(resp.take_string().await.unwrap(), "/unix/path?a=1");
+
+        let resp = TestClient::get("http://127.0.0.1:5800/missing/path")
+            .send(&service)
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::BAD_GATEWAY);
+
+        assert_eq!(
+            super::unix_path(&super::unix_authority(path)).unwrap(),
+            path
+        );
+    }
+}
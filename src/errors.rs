@@ -2,58 +2,186 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::condition::preferred_media_type;
+use crate::validation::Report;
 use async_trait::async_trait;
-use salvo::http::errors::StatusError;
-use salvo::prelude::{Depot, Json, Request, Response, Writer};
+use salvo::prelude::{Depot, Json, Request, Response, StatusCode, Text, Writer};
 use serde::Serialize;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("Invalid proxy URL: `{0}")]
     InvalidURLForProxy(String),
 
+    #[error("Invalid configuration:\n{0}")]
+    InvalidConfig(#[from] Report),
+
     #[error("IO error: `{0}`")]
     IOError(#[from] std::io::Error),
 
     #[error("Http error: `{0}`")]
     HttpError(#[from] http::Error),
 
-    #[error("Hyper error: `{0}`")]
-    HyperError(#[from] hyper::Error),
+    #[error("Unable to connect to the upstream: `{0}`")]
+    UpstreamConnect(hyper::Error),
+
+    #[error("The upstream timed out: `{0}`")]
+    UpstreamTimeout(hyper::Error),
+
+    #[error("Invalid upstream response: `{0}`")]
+    UpstreamResponse(hyper::Error),
+
+    #[error("Invalid request body: `{0}`")]
+    ClientBody(hyper::Error),
+
+    #[error("Unable to send the request to the upstream: `{0}`")]
+    UpstreamRequest(hyper::Error),
+
+    #[error("Timed out connecting to the upstream after {0:?}")]
+    ConnectTimeout(Duration),
+
+    #[error("Timed out waiting for the upstream response after {0:?}")]
+    FirstByteTimeout(Duration),
+
+    #[error("Timed out after {0:?}")]
+    TotalTimeout(Duration),
+
+    #[error("Timed out waiting for the upstream response body after {0:?}")]
+    IdleBodyTimeout(Duration),
 }
 
-#[async_trait]
-impl Writer for Error {
-    async fn write(mut self, _req: &mut Request, _depot: &mut Depot, res: &mut Response) {
-        #[derive(Serialize)]
-        struct ErrorResponse {
-            error: String,
+// The errors of the exchanges with the upstreams. The errors reading the
+// request body are the client's fault: they are mapped to `ClientBody` where
+// the body is read. The other misuses of hyper are ours.
+impl From<hyper::Error> for Error {
+    fn from(e: hyper::Error) -> Error {
+        if e.is_connect() {
+            Error::UpstreamConnect(e)
+        } else if e.is_timeout() {
+            Error::UpstreamTimeout(e)
+        } else if e.is_user() {
+            Error::UpstreamRequest(e)
+        } else {
+            Error::UpstreamResponse(e)
         }
+    }
+}
 
+impl Error {
+    pub fn status_code(&self) -> StatusCode {
         match self {
-            Error::InvalidURLForProxy(_e) => panic!("We should not be here"),
-
-            Error::IOError(e) => {
-                res.set_status_error(StatusError::bad_request());
-                res.render(Json(ErrorResponse {
-                    error: e.to_string(),
-                }));
-            }
-
-            Error::HttpError(e) => {
-                res.set_status_error(StatusError::bad_request());
-                res.render(Json(ErrorResponse {
-                    error: e.to_string(),
-                }));
-            }
-
-            Error::HyperError(e) => {
-                res.set_status_error(StatusError::bad_request());
-                res.render(Json(ErrorResponse {
-                    error: e.to_string(),
-                }));
-            }
+            Error::UpstreamConnect(_) | Error::UpstreamResponse(_) => StatusCode::BAD_GATEWAY,
+            Error::UpstreamTimeout(_)
+            | Error::ConnectTimeout(_)
+            | Error::FirstByteTimeout(_)
+            | Error::TotalTimeout(_)
+            | Error::IdleBodyTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::ClientBody(_) => StatusCode::BAD_REQUEST,
+            Error::UpstreamRequest(_)
+            | Error::InvalidURLForProxy(_)
+            | Error::InvalidConfig(_)
+            | Error::IOError(_)
+            | Error::HttpError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Renders an error as JSON, or as HTML for the clients preferring it.
+pub fn render_error(req: &Request, res: &mut Response, status: StatusCode, message: &str) {
+    #[derive(Serialize)]
+    struct ErrorResponse<'a> {
+        status: u16,
+        error: &'a str,
+    }
+
+    res.set_status_code(status);
+
+    match preferred_media_type(req, &["application/json", "text/html"]) {
+        Some("text/html") => {
+            let title = format!(
+                "{} {}",
+                status.as_u16(),
+                status.canonical_reason().unwrap_or_default()
+            );
+            res.render(Text::Html(format!(
+                "<!DOCTYPE html>\n<html>\n<head><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n<p>{1}</p>\n</body>\n</html>\n",
+                escape_html(&title),
+                escape_html(message)
+            )));
+        }
+        _ => res.render(Json(ErrorResponse {
+            status: status.as_u16(),
+            error: message,
+        })),
+    }
+}
+
+#[async_trait]
+impl Writer for Error {
+    async fn write(mut self, req: &mut Request, _depot: &mut Depot, res: &mut Response) {
+        let status = self.status_code();
+        if status.is_server_error() {
+            tracing::error!(target: "Error", status=status.as_u16(), error=self.to_string(), "request failed");
+        }
+        render_error(req, res, status, &self.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::http::StatusCode;
+    use salvo::prelude::*;
+    use salvo::test::{ResponseExt, TestClient};
+
+    #[handler]
+    async fn bad_gateway(req: &mut Request, res: &mut Response) {
+        super::render_error(req, res, StatusCode::BAD_GATEWAY, "<upstream> down");
+    }
+
+    #[tokio::test]
+    async fn test_render_error() {
+        let router = || Router::new().handle(bad_gateway);
+
+        let mut resp = TestClient::get("http://127.0.0.1:5800/")
+            .send(router())
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            resp.take_string().await.unwrap(),
+            r#"{"status":502,"error":"<upstream> down"}"#
+        );
+
+        let mut resp = TestClient::get("http://127.0.0.1:5800/")
+            .add_header(
+                "accept",
+                "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+                true,
+            )
+            .send(router())
+            .await;
+        assert!(resp.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        let body = resp.take_string().await.unwrap();
+        assert!(body.contains("<h1>502 Bad Gateway</h1>"));
+        assert!(body.contains("&lt;upstream&gt; down"));
+
+        let mut resp = TestClient::get("http://127.0.0.1:5800/")
+            .add_header("accept", "application/json, text/html;q=0.5", true)
+            .send(router())
+            .await;
+        assert!(resp.take_string().await.unwrap().starts_with('{'));
+    }
+}
@@ -0,0 +1,326 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::config::ConfigForwardedHeaders;
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use salvo::prelude::Request;
+use std::net::IpAddr;
+
+// Inserted in the request extensions when the request has been received over
+// TLS.
+#[derive(Clone, Copy, Debug)]
+pub struct TlsConnection;
+
+// The headers describing a single connection, never forwarded (RFC 7230,
+// section 6.1), in addition to the ones listed by `Connection`.
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+// `TE: trailers` is kept: it tells that the client accepts trailers, and is the
+// only value allowed over HTTP/2 (RFC 9113, section 8.2.2).
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let trailers = headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| coding.trim().eq_ignore_ascii_case("trailers"));
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+    if trailers {
+        headers.insert(header::TE, HeaderValue::from_static("trailers"));
+    }
+}
+
+// The protocol a request asks to switch to, when `Connection` lists `upgrade`.
+pub fn upgrade_protocol(headers: &HeaderMap) -> Option<HeaderValue> {
+    let upgrade = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| name.trim().eq_ignore_ascii_case("upgrade"));
+
+    match upgrade {
+        true => headers.get(header::UPGRADE).cloned(),
+        false => None,
+    }
+}
+
+// Whether a response is a stream of Server-Sent Events.
+pub fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("text/event-stream"))
+}
+
+pub fn client_ip(req: &Request) -> Option<IpAddr> {
+    let addr = req.remote_addr()?;
+    match (addr.as_ipv4(), addr.as_ipv6()) {
+        (Some(addr), _) => Some(IpAddr::V4(*addr.ip())),
+        (_, Some(addr)) => Some(IpAddr::V6(*addr.ip())),
+        _ => None,
+    }
+}
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_REAL_IP: &str = "x-real-ip";
+
+// The headers telling the upstreams about the client and the original
+// request. The ones sent by the client are dropped, unless it is trusted: it
+// is then another proxy, and the client IP is appended to its values.
+#[derive(Debug, Clone)]
+pub struct ForwardedHeaders {
+    x_forwarded_for: bool,
+    x_forwarded_proto: bool,
+    x_forwarded_host: bool,
+    x_real_ip: bool,
+    forwarded: bool,
+    trust_incoming: bool,
+}
+
+impl Default for ForwardedHeaders {
+    fn default() -> ForwardedHeaders {
+        ForwardedHeaders::new(&ConfigForwardedHeaders::default())
+    }
+}
+
+// Joins the values of a header, as a list.
+fn joined(headers: &HeaderMap, name: &str) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+fn append(existing: Option<String>, value: &str) -> String {
+    match existing {
+        Some(existing) => format!("{}, {}", existing, value),
+        None => value.to_string(),
+    }
+}
+
+impl ForwardedHeaders {
+    pub fn new(config: &ConfigForwardedHeaders) -> ForwardedHeaders {
+        ForwardedHeaders {
+            x_forwarded_for: config.x_forwarded_for.unwrap_or(true),
+            x_forwarded_proto: config.x_forwarded_proto.unwrap_or(true),
+            x_forwarded_host: config.x_forwarded_host.unwrap_or(true),
+            x_real_ip: config.x_real_ip.unwrap_or(true),
+            forwarded: config.forwarded.unwrap_or(false),
+            trust_incoming: config.trust_incoming.unwrap_or(false),
+        }
+    }
+
+    // Sets the headers of the request sent to the upstream.
+    pub fn apply(&self, req: &Request, headers: &mut HeaderMap) {
+        let ip = client_ip(req);
+        let proto = match req.extensions().get::<TlsConnection>() {
+            Some(_) => "https",
+            None => "http",
+        };
+        let host = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .map(|host| host.to_string())
+            .or_else(|| req.uri().authority().map(|authority| authority.to_string()));
+
+        self.set(headers, ip, proto, host);
+    }
+
+    fn set(&self, headers: &mut HeaderMap, ip: Option<IpAddr>, proto: &str, host: Option<String>) {
+        let incoming = |name: &str| match self.trust_incoming {
+            true => joined(headers, name),
+            false => None,
+        };
+        let x_forwarded_for = incoming(X_FORWARDED_FOR);
+        let x_forwarded_proto = incoming(X_FORWARDED_PROTO);
+        let x_forwarded_host = incoming(X_FORWARDED_HOST);
+        let x_real_ip = incoming(X_REAL_IP);
+        let forwarded = incoming(header::FORWARDED.as_str());
+
+        for name in [
+            X_FORWARDED_FOR,
+            X_FORWARDED_PROTO,
+            X_FORWARDED_HOST,
+            X_REAL_IP,
+            header::FORWARDED.as_str(),
+        ] {
+            headers.remove(name);
+        }
+
+        let mut set = |name: &'static str, value: Option<String>| {
+            if let Some(value) = value.and_then(|value| HeaderValue::from_str(&value).ok()) {
+                headers.insert(name, value);
+            }
+        };
+
+        if self.x_forwarded_for {
+            set(
+                X_FORWARDED_FOR,
+                match ip {
+                    Some(ip) => Some(append(x_forwarded_for, &ip.to_string())),
+                    None => x_forwarded_for,
+                },
+            );
+        }
+        if self.x_forwarded_proto {
+            set(
+                X_FORWARDED_PROTO,
+                x_forwarded_proto.or(Some(proto.to_string())),
+            );
+        }
+        if self.x_forwarded_host {
+            set(X_FORWARDED_HOST, x_forwarded_host.or(host.clone()));
+        }
+        if self.x_real_ip {
+            set(X_REAL_IP, x_real_ip.or(ip.map(|ip| ip.to_string())));
+        }
+        if self.forwarded {
+            // IPv6 addresses are quoted, and the host too as it may contain a
+            // port.
+            let mut element = match ip {
+                Some(IpAddr::V6(ip)) => format!("for=\"[{}]\"", ip),
+                Some(ip) => format!("for={}", ip),
+                None => "for=unknown".to_string(),
+            };
+            if let Some(host) = &host {
+                element.push_str(&format!(";host=\"{}\"", host.replace('"', "")));
+            }
+            element.push_str(&format!(";proto={}", proto));
+            set(
+                header::FORWARDED.as_str(),
+                Some(append(forwarded, &element)),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ConfigForwardedHeaders;
+    use http::header::HeaderMap;
+
+    fn headers(values: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut headers = HeaderMap::new();
+        headers.insert("connection", "keep-alive, x-private".parse().unwrap());
+        headers.insert("keep-alive", "timeout=5".parse().unwrap());
+        headers.insert("x-private", "1".parse().unwrap());
+        headers.insert("transfer-encoding", "chunked".parse().unwrap());
+        headers.insert("upgrade", "websocket".parse().unwrap());
+        headers.insert("accept", "*/*".parse().unwrap());
+
+        super::strip_hop_by_hop(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["accept"], "*/*");
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop_te() {
+        let mut trailers = headers(&[("connection", "te"), ("te", "gzip, Trailers")]);
+        super::strip_hop_by_hop(&mut trailers);
+        assert_eq!(trailers.len(), 1);
+        assert_eq!(trailers["te"], "trailers");
+
+        let mut compressed = headers(&[("te", "gzip")]);
+        super::strip_hop_by_hop(&mut compressed);
+        assert!(compressed.is_empty());
+    }
+
+    #[test]
+    fn test_upgrade_protocol() {
+        let upgrade = headers(&[
+            ("connection", "keep-alive, Upgrade"),
+            ("upgrade", "websocket"),
+        ]);
+        assert_eq!(super::upgrade_protocol(&upgrade).unwrap(), "websocket");
+
+        let missing = headers(&[("connection", "upgrade")]);
+        assert!(super::upgrade_protocol(&missing).is_none());
+
+        let unlisted = headers(&[("connection", "keep-alive"), ("upgrade", "websocket")]);
+        assert!(super::upgrade_protocol(&unlisted).is_none());
+    }
+
+    #[test]
+    fn test_is_event_stream() {
+        let events = headers(&[("content-type", "text/event-stream; charset=utf-8")]);
+        assert!(super::is_event_stream(&events));
+        assert!(!super::is_event_stream(&headers(&[(
+            "content-type",
+            "text/html"
+        )])));
+        assert!(!super::is_event_stream(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_forwarded_headers() {
+        let incoming = headers(&[
+            ("x-forwarded-for", "203.0.113.7"),
+            ("x-real-ip", "203.0.113.7"),
+        ]);
+        let ip = Some("192.0.2.1".parse().unwrap());
+        let host = Some("mozilla.social".to_string());
+
+        let mut headers = incoming.clone();
+        super::ForwardedHeaders::default().set(&mut headers, ip, "http", host.clone());
+        assert_eq!(headers["x-forwarded-for"], "192.0.2.1");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["x-forwarded-host"], "mozilla.social");
+        assert_eq!(headers["x-real-ip"], "192.0.2.1");
+        assert!(!headers.contains_key("forwarded"));
+
+        let config = ConfigForwardedHeaders {
+            x_real_ip: Some(false),
+            forwarded: Some(true),
+            trust_incoming: Some(true),
+            ..Default::default()
+        };
+        let mut headers = incoming.clone();
+        super::ForwardedHeaders::new(&config).set(&mut headers, ip, "https", host);
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.7, 192.0.2.1");
+        assert!(!headers.contains_key("x-real-ip"));
+        assert_eq!(
+            headers["forwarded"],
+            "for=192.0.2.1;host=\"mozilla.social\";proto=https"
+        );
+    }
+}
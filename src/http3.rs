@@ -0,0 +1,273 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::headers::{self, TlsConnection};
+use crate::server::SharedService;
+use h3::error::{Code, StreamError};
+use h3::server::RequestStream;
+use http::header;
+use hyper::body::{Buf, Bytes, HttpBody};
+use hyper::service::Service as _;
+use hyper::Body;
+use std::net::SocketAddr;
+
+type SendStream = RequestStream<h3_quinn::SendStream<Bytes>, Bytes>;
+type RecvStream = RequestStream<h3_quinn::RecvStream, Bytes>;
+
+// Serves HTTP/3 on the QUIC connections accepted by `endpoint`, with the same
+// service as the TCP listener.
+pub async fn serve(endpoint: quinn::Endpoint, service: SharedService) {
+    while let Some(incoming) = endpoint.accept().await {
+        let service = service.clone();
+        tokio::spawn(async move {
+            let remote_addr = incoming.remote_address();
+            let connection = match incoming.await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    tracing::debug!(target: "Http3", remote_addr=remote_addr.to_string(), error=e.to_string(), "QUIC handshake failed");
+                    return;
+                }
+            };
+
+            let mut connection = match h3::server::Connection::new(h3_quinn::Connection::new(
+                connection,
+            ))
+            .await
+            {
+                Ok(connection) => connection,
+                Err(e) => {
+                    tracing::debug!(target: "Http3", remote_addr=remote_addr.to_string(), error=e.to_string(), "HTTP/3 handshake failed");
+                    return;
+                }
+            };
+
+            loop {
+                match connection.accept().await {
+                    Ok(Some(resolver)) => {
+                        let service = service.clone();
+                        tokio::spawn(async move {
+                            let result = match resolver.resolve_request().await {
+                                Ok((request, stream)) => {
+                                    let (send, recv) = stream.split();
+                                    handle(request, send, recv, service, remote_addr).await
+                                }
+                                Err(e) => Err(e),
+                            };
+                            if let Err(e) = result {
+                                tracing::debug!(target: "Http3", remote_addr=remote_addr.to_string(), error=e.to_string(), "request error");
+                            }
+                        });
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        tracing::debug!(target: "Http3", remote_addr=remote_addr.to_string(), error=e.to_string(), "connection error");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+async fn handle(
+    request: h3_http::Request<()>,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    service: SharedService,
+    remote_addr: SocketAddr,
+) -> Result<(), StreamError> {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            match recv.recv_data().await {
+                Ok(Some(mut data)) => {
+                    let chunk = data.copy_to_bytes(data.remaining());
+                    if sender.send_data(chunk).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    sender.abort();
+                    break;
+                }
+            }
+        }
+    });
+
+    let request = match hyper_request(request, body) {
+        Some(request) => request,
+        None => {
+            let response = h3_http::Response::builder()
+                .status(h3_http::StatusCode::BAD_REQUEST)
+                .body(())
+                .unwrap();
+            send.send_response(response).await?;
+            return send.finish().await;
+        }
+    };
+
+    // The salvo handler never fails: errors are rendered as responses.
+    let response = match service
+        .current()
+        .hyper_handler(Some(remote_addr.into()))
+        .call(request)
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::error!(target: "Http3", error=e.to_string(), "unable to handle the request");
+            send.stop_stream(Code::H3_INTERNAL_ERROR);
+            return Ok(());
+        }
+    };
+
+    let (parts, mut body) = response.into_parts();
+    let mut headers = parts.headers;
+    headers::strip_hop_by_hop(&mut headers);
+
+    let mut response = h3_http::Response::builder().status(parts.status.as_u16());
+    for (name, value) in &headers {
+        response = response.header(name.as_str(), value.as_bytes());
+    }
+    send.send_response(response.body(()).unwrap()).await?;
+
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => send.send_data(chunk).await?,
+            Err(e) => {
+                tracing::debug!(target: "Http3", error=e.to_string(), "response body error");
+                send.stop_stream(Code::H3_INTERNAL_ERROR);
+                return Ok(());
+            }
+        }
+    }
+    send.finish().await
+}
+
+// Converts an HTTP/3 request to the hyper one served by the routers. The
+// authority becomes the Host header, as in the other HTTP versions.
+fn hyper_request(request: h3_http::Request<()>, body: Body) -> Option<hyper::Request<Body>> {
+    let (parts, ()) = request.into_parts();
+
+    let mut request = hyper::Request::builder()
+        .method(parts.method.as_str())
+        .uri(parts.uri.to_string())
+        .version(http::Version::HTTP_3);
+    for (name, value) in &parts.headers {
+        request = request.header(name.as_str(), value.as_bytes());
+    }
+    if let (false, Some(authority)) = (
+        parts.headers.contains_key(h3_http::header::HOST),
+        parts.uri.authority(),
+    ) {
+        request = request.header(header::HOST, authority.as_str());
+    }
+
+    let mut request = request.body(body).ok()?;
+    request.extensions_mut().insert(TlsConnection);
+    Some(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use crate::tls::load_certs;
+    use hyper::body::{Buf, Bytes};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::version::TLS13;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+    type Sender = h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>;
+
+    // Opens an HTTP/3 connection trusting the test CA.
+    async fn connect(addr: &str, server_name: &str) -> Sender {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(load_certs("tests/certs/ca.pem").unwrap().remove(0))
+            .unwrap();
+        let mut tls = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_protocol_versions(&[&TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(tls).unwrap();
+
+        let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        let connection = endpoint
+            .connect(addr.parse().unwrap(), server_name)
+            .unwrap()
+            .await
+            .unwrap();
+
+        let (mut driver, sender) = h3::client::new(h3_quinn::Connection::new(connection))
+            .await
+            .unwrap();
+        tokio::spawn(async move { std::future::poll_fn(|cx| driver.poll_close(cx)).await });
+        sender
+    }
+
+    #[tokio::test]
+    async fn test_http3_server() {
+        crate::proxy::tests::upstream("127.0.0.1:5927");
+
+        let config = Config::create_from_filename("tests/configs/027_http3.yaml").unwrap();
+        let mut server = config.server.clone();
+        tokio::spawn(async move { crate::server::run(&config).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let mut sender = connect("127.0.0.1:5926", "localhost").await;
+
+        let request = h3_http::Request::get("https://localhost/test1")
+            .body(())
+            .unwrap();
+        let mut stream = sender.send_request(request).await.unwrap();
+        stream.finish().await.unwrap();
+        let resp = stream.recv_response().await.unwrap();
+        assert_eq!(resp.status(), h3_http::StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(resp.headers()["location"], "test");
+
+        // The request body is forwarded, with the authority as Host header.
+        let request = h3_http::Request::post("https://localhost/proxy/path")
+            .body(())
+            .unwrap();
+        let mut stream = sender.send_request(request).await.unwrap();
+        stream.send_data(Bytes::from("hello")).await.unwrap();
+        stream.finish().await.unwrap();
+        let resp = stream.recv_response().await.unwrap();
+        assert_eq!(resp.status(), h3_http::StatusCode::OK);
+        assert_eq!(resp.headers()["x-body-size"], "5");
+        assert_eq!(resp.headers()["x-host"], "localhost");
+        let mut body = Vec::new();
+        while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+            body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+        }
+        assert_eq!(body, b"/proxy/path");
+
+        // The TCP listener advertises the HTTP/3 one.
+        let stream = crate::tls::tests::connect("127.0.0.1:5926", "localhost").await;
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let resp = sender
+            .send_request(
+                hyper::Request::get("/test1")
+                    .header("host", "localhost")
+                    .body(hyper::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()["alt-svc"], "h3=\":5926\"; ma=3600");
+
+        server.listeners.as_mut().unwrap()[0].tls = None;
+        match crate::server::create_services(&server, &[], &Default::default()) {
+            Err(report) => assert_eq!(report.issues.len(), 1),
+            Ok(_) => panic!("`http3` without `tls` should be reported"),
+        }
+    }
+}
@@ -4,17 +4,21 @@
 
 mod action;
 mod catchers;
+mod cli;
 mod condition;
 mod config;
+mod connector;
 mod errors;
+mod headers;
+mod http3;
 mod proxy;
+mod reload;
 mod routers;
 mod server;
+mod tls;
+mod validation;
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt().init();
-
-    let config = config::Config::create();
-    server::run(&config).await
+    cli::run().await
 }
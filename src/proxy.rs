@@ -2,64 +2,1889 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::config::{
+    ConfigRuleBalance, ConfigRuleHealthCheck, ConfigRulePool, ConfigRuleRetry, ConfigRuleUpgrade,
+    ConfigTimeouts,
+};
+use crate::connector::{
+    unix_authority, UpstreamConnector, UpstreamProtocol, UpstreamTls, UNIX_SCHEME,
+};
 use crate::errors::Error;
-use hyper::{client::conn, Body};
-use tokio::net::TcpStream;
+use crate::headers::{self, ForwardedHeaders};
+use futures_core::Stream;
+use http::header::{self, HeaderName, HeaderValue};
+use http::{Method, StatusCode};
+use hyper::body::{Bytes, HttpBody};
+use hyper::upgrade::OnUpgrade;
+use hyper::{Body, Client};
+use rand::Rng;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Sleep;
 
 use salvo::prelude::{Request, Response};
 
+// Weights are expanded in the round robin schedule and in the hashing ring:
+// they are kept small.
+pub const MAX_WEIGHT: u32 = 1000;
+
+// Each retry waits for the backoff, doubled every time: the number of retries
+// and the delay between two of them are bounded.
+pub const MAX_RETRY_ATTEMPTS: u32 = 10;
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+// The number of points of each upstream, per unit of weight, on the
+// consistent hashing ring.
+const RING_POINTS: u32 = 40;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+    Random,
+    // Hashes the value of the header, or the client IP.
+    Hash(Option<HeaderName>),
+}
+
+impl Strategy {
+    pub fn parse(balance: &ConfigRuleBalance) -> Result<Strategy, String> {
+        let strategy = match balance.strategy.as_deref().unwrap_or("round_robin") {
+            "round_robin" => Strategy::RoundRobin,
+            "least_connections" => Strategy::LeastConnections,
+            "random" => Strategy::Random,
+            "hash" => {
+                let header = match balance.header.as_deref() {
+                    Some(header) => Some(
+                        HeaderName::from_bytes(header.as_bytes())
+                            .map_err(|_| format!("invalid header `{}`", header))?,
+                    ),
+                    None => None,
+                };
+                return Ok(Strategy::Hash(header));
+            }
+            strategy => return Err(format!("invalid strategy `{}`", strategy)),
+        };
+
+        if balance.header.is_some() {
+            return Err("`header` is only used by the `hash` strategy".to_string());
+        }
+        Ok(strategy)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ActiveHealthCheck {
+    path: String,
+    status: StatusCode,
+    interval: Duration,
+    timeout: Duration,
+    healthy_threshold: u32,
+    unhealthy_threshold: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct PassiveHealthCheck {
+    max_failures: u32,
+    ejection_time: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HealthCheck {
+    active: Option<ActiveHealthCheck>,
+    passive: Option<PassiveHealthCheck>,
+}
+
+impl HealthCheck {
+    pub fn parse(config: &ConfigRuleHealthCheck) -> Result<HealthCheck, String> {
+        let active = match &config.active {
+            Some(active) => {
+                if !active.path.starts_with('/') {
+                    return Err(format!("the path `{}` must start with `/`", active.path));
+                }
+                let status = active.status.unwrap_or(200);
+                let status = StatusCode::from_u16(status)
+                    .map_err(|_| format!("invalid status code {}", status))?;
+                let interval = active.interval.unwrap_or(10);
+                let timeout = active.timeout.unwrap_or(2);
+                let healthy_threshold = active.healthy_threshold.unwrap_or(2);
+                let unhealthy_threshold = active.unhealthy_threshold.unwrap_or(3);
+                if interval == 0 || timeout == 0 {
+                    return Err("`interval` and `timeout` must be at least 1 second".to_string());
+                }
+                if healthy_threshold == 0 || unhealthy_threshold == 0 {
+                    return Err("the thresholds must be at least 1".to_string());
+                }
+                Some(ActiveHealthCheck {
+                    path: active.path.clone(),
+                    status,
+                    interval: Duration::from_secs(interval),
+                    timeout: Duration::from_secs(timeout),
+                    healthy_threshold,
+                    unhealthy_threshold,
+                })
+            }
+            None => None,
+        };
+
+        let passive = match &config.passive {
+            Some(passive) => {
+                let max_failures = passive.max_failures.unwrap_or(3);
+                if max_failures == 0 {
+                    return Err("`max_failures` must be at least 1".to_string());
+                }
+                Some(PassiveHealthCheck {
+                    max_failures,
+                    ejection_time: Duration::from_secs(passive.ejection_time.unwrap_or(30)),
+                })
+            }
+            None => None,
+        };
+
+        Ok(HealthCheck { active, passive })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Retry {
+    attempts: u32,
+    backoff: Duration,
+    methods: Vec<Method>,
+    statuses: Vec<StatusCode>,
+    max_body_size: usize,
+}
+
+impl Retry {
+    pub fn parse(config: &ConfigRuleRetry) -> Result<Retry, String> {
+        let methods = match &config.methods {
+            Some(methods) => methods
+                .iter()
+                .map(|name| {
+                    Method::from_bytes(name.as_bytes())
+                        .map_err(|_| format!("invalid method `{}`", name))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None => vec![
+                Method::GET,
+                Method::HEAD,
+                Method::OPTIONS,
+                Method::PUT,
+                Method::DELETE,
+                Method::TRACE,
+            ],
+        };
+
+        let statuses = config
+            .statuses
+            .clone()
+            .unwrap_or_else(|| vec![502, 503, 504])
+            .into_iter()
+            .map(|status| {
+                StatusCode::from_u16(status).map_err(|_| format!("invalid status code {}", status))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let attempts = config.attempts.unwrap_or(2);
+        if attempts > MAX_RETRY_ATTEMPTS {
+            return Err(format!("`attempts` must be at most {}", MAX_RETRY_ATTEMPTS));
+        }
+
+        Ok(Retry {
+            attempts,
+            backoff: Duration::from_millis(config.backoff.unwrap_or(100)),
+            methods,
+            statuses,
+            max_body_size: config.max_body_size.unwrap_or(64 * 1024),
+        })
+    }
+
+    // The delay before the `attempt`th retry, starting from 1.
+    fn delay(&self, attempt: u32) -> Duration {
+        self.backoff
+            .checked_mul(2u32.saturating_pow(attempt - 1))
+            .map_or(MAX_RETRY_BACKOFF, |delay| delay.min(MAX_RETRY_BACKOFF))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Timeouts {
+    connect: Option<Duration>,
+    first_byte: Option<Duration>,
+    total: Option<Duration>,
+    idle_body: Option<Duration>,
+}
+
+impl Timeouts {
+    // The timeouts of the rule, defaulting to the ones of the server.
+    pub fn parse(
+        rule: Option<&ConfigTimeouts>,
+        server: Option<&ConfigTimeouts>,
+    ) -> Result<Timeouts, String> {
+        let timeout = |name: &str, field: fn(&ConfigTimeouts) -> Option<f64>| match rule
+            .and_then(field)
+            .or_else(|| server.and_then(field))
+        {
+            Some(seconds) => match Duration::try_from_secs_f64(seconds) {
+                Ok(duration) if !duration.is_zero() => Ok(Some(duration)),
+                _ => Err(format!("invalid `{}` timeout {}", name, seconds)),
+            },
+            None => Ok(None),
+        };
+
+        Ok(Timeouts {
+            connect: timeout("connect", |t| t.connect)?,
+            first_byte: timeout("first_byte", |t| t.first_byte)?,
+            total: timeout("total", |t| t.total)?,
+            idle_body: timeout("idle_body", |t| t.idle_body)?,
+        })
+    }
+}
+
+// Tunneling of the requests switching protocols, when allowed.
+#[derive(Debug, Clone)]
+pub struct Upgrade {
+    idle_timeout: Duration,
+}
+
+impl Upgrade {
+    pub fn parse(config: &ConfigRuleUpgrade) -> Result<Option<Upgrade>, String> {
+        if !config.allow.unwrap_or(false) {
+            return Ok(None);
+        }
+
+        let seconds = config.idle_timeout.unwrap_or(300.0);
+        match Duration::try_from_secs_f64(seconds) {
+            Ok(idle_timeout) if !idle_timeout.is_zero() => Ok(Some(Upgrade { idle_timeout })),
+            _ => Err(format!("invalid `idle_timeout` {}", seconds)),
+        }
+    }
+}
+
+// A request body read to be replayed on retries, unless larger than the limit:
+// the part already read is then sent before the rest.
+enum BufferedBody {
+    Complete(Bytes),
+    Partial(Body),
+}
+
+async fn buffer_body(mut body: Body, max_size: usize) -> Result<BufferedBody, hyper::Error> {
+    if HttpBody::size_hint(&body).lower() as usize > max_size {
+        return Ok(BufferedBody::Partial(body));
+    }
+
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.data().await {
+        buffer.extend_from_slice(&chunk?);
+        if buffer.len() > max_size {
+            return Ok(BufferedBody::Partial(Body::wrap_stream(PrefixedBody {
+                prefix: Some(Bytes::from(buffer)),
+                body,
+            })));
+        }
+    }
+    Ok(BufferedBody::Complete(Bytes::from(buffer)))
+}
+
+struct PrefixedBody {
+    prefix: Option<Bytes>,
+    body: Body,
+}
+
+impl Stream for PrefixedBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.prefix.take() {
+            Some(prefix) => Poll::Ready(Some(Ok(prefix))),
+            None => Pin::new(&mut self.body).poll_data(cx),
+        }
+    }
+}
+
+// The request body streamed to the upstream. Its read errors are kept aside:
+// hyper reports them as errors of the upstream request.
+struct ClientBody {
+    body: Body,
+    error: Arc<Mutex<Option<hyper::Error>>>,
+}
+
+impl Stream for ClientBody {
+    type Item = Result<Bytes, std::io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let chunk = match Pin::new(&mut self.body).poll_data(cx) {
+            Poll::Ready(chunk) => chunk,
+            Poll::Pending => return Poll::Pending,
+        };
+        Poll::Ready(chunk.map(|chunk| {
+            chunk.map_err(|e| {
+                *self.error.lock().unwrap() = Some(e);
+                std::io::Error::other("unable to read the request body")
+            })
+        }))
+    }
+}
+
+// The health of an upstream: the result of the active probes, and the passive
+// ejection after consecutive connection failures.
+#[derive(Debug)]
+struct Health {
+    healthy: AtomicBool,
+    failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Health {
+    fn new() -> Health {
+        Health {
+            healthy: AtomicBool::new(true),
+            failures: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+        }
+    }
+
+    fn is_ejected(&self) -> bool {
+        let mut ejected_until = self.ejected_until.lock().unwrap();
+        match *ejected_until {
+            Some(until) if until > Instant::now() => true,
+            Some(_) => {
+                *ejected_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst) && !self.is_ejected()
+    }
+}
+
+struct Upstream {
+    scheme: &'static str,
+    // The host and port, or the path of a Unix socket.
+    address: String,
+    // The authority of the URIs of the requests.
+    authority: String,
+    // Prepended to the path of the requests.
+    base_path: String,
+    weight: u32,
+    // The requests being proxied to this upstream, including the ones still
+    // streaming their response body.
+    in_flight: Arc<AtomicUsize>,
+    health: Arc<Health>,
+}
+
+impl Upstream {
+    // The Host header of the upstream.
+    fn host(&self) -> &str {
+        match self.scheme {
+            UNIX_SCHEME => "localhost",
+            _ => &self.address,
+        }
+    }
+
+    fn connect_failed(&self, passive: &PassiveHealthCheck) {
+        let failures = self.health.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if failures >= passive.max_failures {
+            self.health.failures.store(0, Ordering::SeqCst);
+            *self.health.ejected_until.lock().unwrap() =
+                Some(Instant::now() + passive.ejection_time);
+            tracing::warn!(target: "Proxy", address=self.address, failures=failures, ejection_time=passive.ejection_time.as_secs(), "ejecting the upstream");
+        }
+    }
+
+    fn connect_succeeded(&self) {
+        if self.health.failures.swap(0, Ordering::SeqCst) > 0 {
+            tracing::info!(target: "Proxy", address=self.address, "the upstream is reachable again");
+        }
+    }
+
+    fn status(&self) -> UpstreamStatus {
+        UpstreamStatus {
+            address: self.address.clone(),
+            weight: self.weight,
+            healthy: self.health.healthy.load(Ordering::SeqCst),
+            ejected: self.health.is_ejected(),
+            failures: self.health.failures.load(Ordering::SeqCst),
+            in_flight: self.in_flight.load(Ordering::SeqCst),
+        }
+    }
+}
+
+// Probes an upstream until its proxy is dropped, updating its health.
+async fn probe(
+    address: String,
+    uri: String,
+    health: Weak<Health>,
+    check: ActiveHealthCheck,
+    client: Client<UpstreamConnector>,
+) {
+    let mut interval = tokio::time::interval(check.interval);
+    let (mut successes, mut failures) = (0, 0);
+
+    loop {
+        interval.tick().await;
+
+        let request = match hyper::Request::get(&uri).body(Body::empty()) {
+            Ok(request) => request,
+            Err(e) => {
+                tracing::error!(target: "Proxy", address=address, error=e.to_string(), "invalid health check request");
+                return;
+            }
+        };
+        let passed = match tokio::time::timeout(check.timeout, client.request(request)).await {
+            Ok(Ok(response)) => response.status() == check.status,
+            _ => false,
+        };
+
+        let health = match health.upgrade() {
+            Some(health) => health,
+            None => return,
+        };
+
+        if passed {
+            successes += 1;
+            failures = 0;
+        } else {
+            failures += 1;
+            successes = 0;
+        }
+
+        let healthy = health.healthy.load(Ordering::SeqCst);
+        if healthy && failures >= check.unhealthy_threshold {
+            health.healthy.store(false, Ordering::SeqCst);
+            tracing::warn!(target: "Proxy", address=address, failures=failures, "the upstream is unhealthy");
+        } else if !healthy && successes >= check.healthy_threshold {
+            health.healthy.store(true, Ordering::SeqCst);
+            tracing::info!(target: "Proxy", address=address, successes=successes, "the upstream is healthy");
+        }
+    }
+}
+
+// Counts a request as in flight while alive.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(counter: &Arc<AtomicUsize>) -> InFlight {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InFlight(counter.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// A response body keeping its request in flight until fully sent. Each chunk
+// is passed on as soon as the upstream sends it, never buffered. It fails
+// when the total deadline of the request passes, or when the upstream sends
+// nothing for the idle timeout. Dropping it before its end, when the client
+// goes away, closes the connection to the upstream.
+struct InFlightBody {
+    body: Body,
+    _in_flight: InFlight,
+    total: Option<(Duration, Pin<Box<Sleep>>)>,
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
+    finished: bool,
+}
+
+impl Drop for InFlightBody {
+    fn drop(&mut self) {
+        if !self.finished {
+            tracing::debug!(target: "Proxy", "response body dropped before its end, cancelling the upstream response");
+        }
+    }
+}
+
+impl Stream for InFlightBody {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if let Some((total, deadline)) = &mut this.total {
+            if deadline.as_mut().poll(cx).is_ready() {
+                tracing::warn!(target: "Proxy", timeout=total.as_secs_f64(), "total timeout while sending the response body");
+                this.finished = true;
+                return Poll::Ready(Some(Err(Error::TotalTimeout(*total))));
+            }
+        }
+        if let Some((idle, deadline)) = &mut this.idle {
+            if deadline.as_mut().poll(cx).is_ready() {
+                tracing::warn!(target: "Proxy", timeout=idle.as_secs_f64(), "idle timeout while sending the response body");
+                this.finished = true;
+                return Poll::Ready(Some(Err(Error::IdleBodyTimeout(*idle))));
+            }
+        }
+
+        match Pin::new(&mut this.body).poll_data(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some((idle, deadline)) = &mut this.idle {
+                    deadline.as_mut().reset(tokio::time::Instant::now() + *idle);
+                }
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Some(Err(e))) => {
+                this.finished = true;
+                Poll::Ready(Some(Err(Error::UpstreamResponse(e))))
+            }
+            Poll::Ready(None) => {
+                this.finished = true;
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct UpstreamStatus {
+    address: String,
+    weight: u32,
+    healthy: bool,
+    ejected: bool,
+    failures: u32,
+    in_flight: usize,
+}
+
+#[derive(Serialize)]
+pub struct ProxyStatus {
+    rule: String,
+    upstreams: Vec<UpstreamStatus>,
+}
+
+// The Host header sent to the upstreams: the one of the client, the address of
+// the upstream, or a fixed value.
+#[derive(Debug, Clone, Default)]
+pub enum HostHeader {
+    #[default]
+    Preserve,
+    Upstream,
+    Override(HeaderValue),
+}
+
+impl HostHeader {
+    pub fn parse(value: &str) -> Result<HostHeader, String> {
+        match value {
+            "preserve" => Ok(HostHeader::Preserve),
+            "upstream" => Ok(HostHeader::Upstream),
+            _ => match HeaderValue::from_str(value) {
+                Ok(host) if !value.is_empty() => Ok(HostHeader::Override(host)),
+                _ => Err(format!("invalid host `{}`", value)),
+            },
+        }
+    }
+}
+
+// How a proxy forwards the requests to its upstreams.
+#[derive(Clone, Default)]
+pub struct ProxyOptions {
+    pub strategy: Strategy,
+    pub health_check: HealthCheck,
+    pub retry: Option<Retry>,
+    pub timeouts: Timeouts,
+    pub forwarded_headers: ForwardedHeaders,
+    pub host_header: HostHeader,
+    pub tls: Option<UpstreamTls>,
+    pub protocol: UpstreamProtocol,
+    pub pool: ConfigRulePool,
+    pub upgrade: Option<Upgrade>,
+}
+
 pub struct Proxy {
-    pub address: String,
+    upstreams: Vec<Upstream>,
+    strategy: Strategy,
+    health_check: HealthCheck,
+    retry: Option<Retry>,
+    timeouts: Timeouts,
+    forwarded_headers: ForwardedHeaders,
+    host_header: HostHeader,
+    upgrade: Option<Upgrade>,
+    // Upstream indexes, each repeated as many times as its weight.
+    schedule: Vec<usize>,
+    next: AtomicUsize,
+    // Sorted points of the consistent hashing ring, with their upstream.
+    ring: Vec<(u64, usize)>,
+    client: Client<UpstreamConnector>,
+}
+
+fn hash(value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
 }
 
 impl Proxy {
-    pub fn create(url: &str) -> Result<Proxy, Error> {
-        match url.parse::<hyper::Uri>() {
-            Ok(url) if url.host().is_none() => {
-                Err(Error::InvalidURLForProxy("Empty host".to_string()))
-            }
-            Ok(url) => Ok(Proxy {
-                address: format!(
-                    "{}:{}",
-                    url.host().unwrap().to_string(),
-                    url.port_u16().unwrap_or(80)
-                ),
-            }),
-            Err(e) => Err(Error::InvalidURLForProxy(e.to_string())),
+    pub fn create(upstreams: &[(&str, u32)], options: ProxyOptions) -> Result<Proxy, Error> {
+        let ProxyOptions {
+            strategy,
+            health_check,
+            retry,
+            timeouts,
+            forwarded_headers,
+            host_header,
+            tls,
+            protocol,
+            pool,
+            upgrade,
+        } = options;
+
+        let mut parsed = Vec::new();
+        for (url, weight) in upstreams {
+            if let Some(path) = url.strip_prefix("unix://") {
+                if !path.starts_with('/') {
+                    return Err(Error::InvalidURLForProxy(
+                        "The path of a Unix socket must be absolute".to_string(),
+                    ));
+                }
+                parsed.push(Upstream {
+                    scheme: UNIX_SCHEME,
+                    address: path.to_string(),
+                    authority: unix_authority(path),
+                    base_path: String::new(),
+                    weight: *weight,
+                    in_flight: Arc::new(AtomicUsize::new(0)),
+                    health: Arc::new(Health::new()),
+                });
+                continue;
+            }
+
+            let url = match url.parse::<hyper::Uri>() {
+                Ok(url) if url.host().is_none() => {
+                    return Err(Error::InvalidURLForProxy("Empty host".to_string()))
+                }
+                Ok(url) => url,
+                Err(e) => return Err(Error::InvalidURLForProxy(e.to_string())),
+            };
+            let (scheme, default_port) = match url.scheme_str() {
+                None | Some("http") => ("http", 80),
+                Some("https") => ("https", 443),
+                Some(scheme) => {
+                    return Err(Error::InvalidURLForProxy(format!(
+                        "Unsupported scheme `{}`",
+                        scheme
+                    )))
+                }
+            };
+            if url.query().is_some() {
+                return Err(Error::InvalidURLForProxy(
+                    "A query string is not supported".to_string(),
+                ));
+            }
+            let address = format!(
+                "{}:{}",
+                url.host().unwrap(),
+                url.port_u16().unwrap_or(default_port)
+            );
+            parsed.push(Upstream {
+                scheme,
+                authority: address.clone(),
+                address,
+                base_path: url.path().trim_end_matches('/').to_string(),
+                weight: *weight,
+                in_flight: Arc::new(AtomicUsize::new(0)),
+                health: Arc::new(Health::new()),
+            });
+        }
+
+        if parsed.is_empty() {
+            return Err(Error::InvalidURLForProxy("No upstreams".to_string()));
+        }
+
+        let schedule = parsed
+            .iter()
+            .enumerate()
+            .flat_map(|(i, upstream)| std::iter::repeat_n(i, upstream.weight as usize))
+            .collect();
+
+        let mut ring: Vec<(u64, usize)> = parsed
+            .iter()
+            .enumerate()
+            .flat_map(|(i, upstream)| {
+                (0..upstream.weight * RING_POINTS)
+                    .map(move |point| (hash((&upstream.address, point)), i))
+            })
+            .collect();
+        ring.sort();
+
+        // Each proxy keeps its own pool of idle keep-alive connections to the
+        // upstream, so that consecutive requests do not pay a TCP connect.
+        let mut builder = Client::builder();
+        if let Some(max_idle) = pool.max_idle {
+            builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(idle_timeout) = pool.idle_timeout {
+            builder.pool_idle_timeout(Duration::from_secs(idle_timeout));
+        }
+        if protocol == UpstreamProtocol::H2c {
+            builder.http2_only(true);
+        }
+
+        // The https upstreams are verified with the default roots, unless the
+        // rule has TLS settings.
+        let tls = match tls {
+            Some(tls) => Some(tls),
+            None if parsed.iter().any(|upstream| upstream.scheme == "https") => Some(
+                UpstreamTls::new(&Default::default(), protocol)
+                    .map_err(Error::InvalidURLForProxy)?,
+            ),
+            None => None,
+        };
+
+        for upstream in &parsed {
+            tracing::info!(target: "Proxy", address=upstream.address, weight=upstream.weight, strategy=?strategy, protocol=?protocol, max_idle=pool.max_idle, idle_timeout=pool.idle_timeout, "creating a pooled client");
+        }
+
+        let client = builder.build(UpstreamConnector::new(timeouts.connect, tls));
+
+        Ok(Proxy {
+            upstreams: parsed,
+            strategy,
+            health_check,
+            retry,
+            timeouts,
+            forwarded_headers,
+            host_header,
+            upgrade,
+            schedule,
+            next: AtomicUsize::new(0),
+            ring,
+            client,
+        })
+    }
+
+    // Starts probing the upstreams, if active health checks are configured.
+    // The probes stop when the proxy is dropped.
+    pub fn start_health_checks(&self) {
+        let check = match &self.health_check.active {
+            Some(check) => check,
+            None => return,
+        };
+
+        for upstream in &self.upstreams {
+            tracing::info!(target: "Proxy", address=upstream.address, path=check.path, interval=check.interval.as_secs(), "starting the health checks");
+            tokio::spawn(probe(
+                upstream.address.clone(),
+                format!("{}://{}{}", upstream.scheme, upstream.authority, check.path),
+                Arc::downgrade(&upstream.health),
+                check.clone(),
+                self.client.clone(),
+            ));
+        }
+    }
+
+    pub fn status(&self, rule: &str) -> ProxyStatus {
+        ProxyStatus {
+            rule: rule.to_string(),
+            upstreams: self.upstreams.iter().map(Upstream::status).collect(),
+        }
+    }
+
+    fn round_robin(&self, available: &[bool]) -> usize {
+        // An available upstream is found within a full turn of the schedule.
+        loop {
+            let i = self.schedule[self.next.fetch_add(1, Ordering::Relaxed) % self.schedule.len()];
+            if available[i] {
+                return i;
+            }
+        }
+    }
+
+    fn hash_key(&self, req: &Request, header: Option<&HeaderName>) -> Option<u64> {
+        match header {
+            Some(header) => req
+                .headers()
+                .get(header)
+                .map(|value| hash(value.as_bytes())),
+            None => headers::client_ip(req).map(hash),
+        }
+    }
+
+    // The upstreams a request may be sent to: the healthy ones not `tried`
+    // yet. When none is healthy, all of them are used: failing open beats
+    // refusing everything.
+    fn available(&self, tried: &[usize]) -> Vec<bool> {
+        let healthy: Vec<bool> = self
+            .upstreams
+            .iter()
+            .map(|upstream| upstream.health.is_available())
+            .collect();
+        let mut available: Vec<bool> = healthy
+            .iter()
+            .enumerate()
+            .map(|(i, healthy)| *healthy && !tried.contains(&i))
+            .collect();
+        if !available.contains(&true) {
+            available = healthy;
+        }
+        if !available.contains(&true) {
+            available = vec![true; self.upstreams.len()];
         }
+        available
     }
 
-    pub async fn handle(&self, req: &mut Request, res: &mut Response) -> Result<(), Error> {
-        let stream = TcpStream::connect(&self.address).await?;
+    // Selects the upstream of a request, among the available ones.
+    fn select(&self, req: &Request, tried: &[usize]) -> usize {
+        if self.upstreams.len() == 1 {
+            return 0;
+        }
+
+        let available = self.available(tried);
+        match &self.strategy {
+            Strategy::RoundRobin => self.round_robin(&available),
+            Strategy::Random => {
+                let schedule: Vec<usize> = self
+                    .schedule
+                    .iter()
+                    .copied()
+                    .filter(|i| available[*i])
+                    .collect();
+                schedule[rand::thread_rng().gen_range(0..schedule.len())]
+            }
+            Strategy::LeastConnections => {
+                // Ties are broken in turn, starting from a rotating offset.
+                let offset = self.next.fetch_add(1, Ordering::Relaxed);
+                let count = self.upstreams.len();
+                (0..count)
+                    .map(|i| (offset + i) % count)
+                    .filter(|i| available[*i])
+                    .min_by(|a, b| {
+                        let (a, b) = (&self.upstreams[*a], &self.upstreams[*b]);
+                        let a_load = a.in_flight.load(Ordering::SeqCst) as u64 * b.weight as u64;
+                        let b_load = b.in_flight.load(Ordering::SeqCst) as u64 * a.weight as u64;
+                        a_load.cmp(&b_load)
+                    })
+                    .unwrap()
+            }
+            Strategy::Hash(header) => match self.hash_key(req, header.as_ref()) {
+                Some(key) => {
+                    // The unavailable upstreams are skipped clockwise.
+                    let point = self.ring.partition_point(|(point, _)| *point < key);
+                    (0..self.ring.len())
+                        .map(|i| self.ring[(point + i) % self.ring.len()].1)
+                        .find(|i| available[*i])
+                        .unwrap()
+                }
+                None => self.round_robin(&available),
+            },
+        }
+    }
+
+    fn upstream_target(&self, upstream: &Upstream, path: &str) -> String {
+        format!(
+            "{}://{}{}{}",
+            upstream.scheme, upstream.authority, upstream.base_path, path
+        )
+    }
+
+    // The URI the request would be forwarded to, with `path` as path and
+    // query. Nothing is selected, which would move the strategy along: the
+    // first available upstream is reported.
+    pub fn target(&self, path: &str) -> String {
+        let available = self.available(&[]);
+        let i = available.iter().position(|available| *available).unwrap();
+        self.upstream_target(&self.upstreams[i], path)
+    }
+
+    pub async fn handle(
+        &self,
+        req: &mut Request,
+        res: &mut Response,
+        path: &str,
+    ) -> Result<(), Error> {
+        let retry = self
+            .retry
+            .as_ref()
+            .filter(|retry| retry.methods.contains(req.method()));
 
-        let (mut sender, connection) = conn::handshake(stream).await?;
+        let mut body = req.take_body().unwrap_or(Body::empty());
+        let mut replay = None;
+        if let Some(retry) = retry {
+            match buffer_body(body, retry.max_body_size)
+                .await
+                .map_err(Error::ClientBody)?
+            {
+                BufferedBody::Complete(bytes) => {
+                    replay = Some(bytes);
+                    body = Body::empty();
+                }
+                BufferedBody::Partial(partial) => {
+                    tracing::debug!(target: "Proxy", max_body_size=retry.max_body_size, "request body too large to be retried");
+                    body = partial;
+                }
+            }
+        }
+        let client_error = Arc::new(Mutex::new(None));
+        let mut body = Some(match body.is_end_stream() {
+            true => body,
+            false => Body::wrap_stream(ClientBody {
+                body,
+                error: client_error.clone(),
+            }),
+        });
 
-        tokio::spawn(async move { connection.await });
+        // The connection of the client is taken over once the upstream has
+        // switched protocols: there is nothing to retry then.
+        let mut client_upgrade = match (&self.upgrade, headers::upgrade_protocol(req.headers())) {
+            (Some(_), Some(protocol)) => req
+                .extensions_mut()
+                .remove::<OnUpgrade>()
+                .map(|on_upgrade| (protocol, on_upgrade)),
+            _ => None,
+        };
 
-        let mut proxied_request = http::Request::builder().uri(req.uri());
-        for (key, value) in req.headers().iter() {
-            proxied_request = proxied_request.header(key, value);
+        let attempts = match (retry, &replay, &client_upgrade) {
+            (Some(retry), Some(_), None) => retry.attempts,
+            _ => 0,
+        };
+        let mut proxied_headers = req.headers().clone();
+        headers::strip_hop_by_hop(&mut proxied_headers);
+        self.forwarded_headers.apply(req, &mut proxied_headers);
+        if let Some((protocol, _)) = &client_upgrade {
+            proxied_headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+            proxied_headers.insert(header::UPGRADE, protocol.clone());
         }
-        let proxied_request = proxied_request.method(req.method());
-        let proxied_request =
-            proxied_request.body(req.take_body().or(Some(Body::from(""))).unwrap())?;
-        let response = sender.send_request(proxied_request).await?;
 
+        let mut tried = Vec::new();
+        let deadline = self
+            .timeouts
+            .total
+            .map(|total| tokio::time::Instant::now() + total);
+
+        loop {
+            let index = self.select(req, &tried);
+            tried.push(index);
+            let upstream = &self.upstreams[index];
+            let in_flight = InFlight::new(&upstream.in_flight);
+            let last = tried.len() as u32 > attempts;
+
+            let mut proxied_request = http::Request::builder()
+                .uri(self.upstream_target(upstream, path))
+                .method(req.method());
+            if let Some(headers) = proxied_request.headers_mut() {
+                *headers = proxied_headers.clone();
+                match &self.host_header {
+                    HostHeader::Preserve => {}
+                    HostHeader::Upstream => {
+                        if let Ok(host) = HeaderValue::from_str(upstream.host()) {
+                            headers.insert(header::HOST, host);
+                        }
+                    }
+                    HostHeader::Override(host) => {
+                        headers.insert(header::HOST, host.clone());
+                    }
+                }
+            }
+            let proxied_request = proxied_request.body(match &replay {
+                Some(bytes) => Body::from(bytes.clone()),
+                None => body.take().unwrap_or(Body::empty()),
+            })?;
+
+            match self.send(proxied_request, deadline).await {
+                Ok(response) => {
+                    upstream.connect_succeeded();
+                    if last
+                        || !retry.is_some_and(|retry| retry.statuses.contains(&response.status()))
+                    {
+                        return match client_upgrade.take() {
+                            Some((_, on_upgrade))
+                                if response.status() == StatusCode::SWITCHING_PROTOCOLS =>
+                            {
+                                self.switch_protocols(
+                                    response, on_upgrade, upstream, in_flight, res,
+                                )
+                            }
+                            _ => self.respond(response, in_flight, deadline, res),
+                        };
+                    }
+                    tracing::warn!(target: "Proxy", address=upstream.address, status=response.status().as_u16(), "retrying the request");
+                }
+                Err(e) => {
+                    if let Some(e) = client_error.lock().unwrap().take() {
+                        return Err(Error::ClientBody(e));
+                    }
+                    if let (Error::UpstreamConnect(_) | Error::ConnectTimeout(_), Some(passive)) =
+                        (&e, &self.health_check.passive)
+                    {
+                        upstream.connect_failed(passive);
+                    }
+                    if last || matches!(e, Error::TotalTimeout(_)) {
+                        return Err(e);
+                    }
+                    tracing::warn!(target: "Proxy", address=upstream.address, error=e.to_string(), "retrying the request");
+                }
+            }
+
+            if let Some(retry) = retry {
+                // No retry can succeed after the total deadline.
+                let delay = retry.delay(tried.len() as u32);
+                match (deadline, self.timeouts.total) {
+                    (Some(deadline), Some(total))
+                        if tokio::time::Instant::now() + delay >= deadline =>
+                    {
+                        tracing::warn!(target: "Proxy", delay=delay.as_secs_f64(), "the total timeout expires before the next retry");
+                        return Err(Error::TotalTimeout(total));
+                    }
+                    _ => tokio::time::sleep(delay).await,
+                }
+            }
+        }
+    }
+
+    // Sends a request to an upstream, waiting for the response headers until
+    // the first byte timeout, or the total deadline.
+    async fn send(
+        &self,
+        request: hyper::Request<Body>,
+        deadline: Option<tokio::time::Instant>,
+    ) -> Result<hyper::Response<Body>, Error> {
+        let response = async {
+            let response = self.client.request(request);
+            let result = match self.timeouts.first_byte {
+                Some(first_byte) => tokio::time::timeout(first_byte, response)
+                    .await
+                    .map_err(|_| Error::FirstByteTimeout(first_byte))?,
+                None => response.await,
+            };
+            result.map_err(|e| self.upstream_error(e))
+        };
+
+        match (deadline, self.timeouts.total) {
+            (Some(deadline), Some(total)) => tokio::time::timeout_at(deadline, response)
+                .await
+                .unwrap_or(Err(Error::TotalTimeout(total))),
+            _ => response.await,
+        }
+    }
+
+    // Connection timeouts are reported by hyper as connection errors.
+    fn upstream_error(&self, e: hyper::Error) -> Error {
+        if let (true, Some(connect)) = (e.is_connect(), self.timeouts.connect) {
+            let mut source = std::error::Error::source(&e);
+            while let Some(error) = source {
+                if let Some(io) = error.downcast_ref::<std::io::Error>() {
+                    if io.kind() == std::io::ErrorKind::TimedOut {
+                        return Error::ConnectTimeout(connect);
+                    }
+                }
+                source = error.source();
+            }
+        }
+        e.into()
+    }
+
+    fn respond(
+        &self,
+        response: hyper::Response<Body>,
+        in_flight: InFlight,
+        deadline: Option<tokio::time::Instant>,
+        res: &mut Response,
+    ) -> Result<(), Error> {
         let (
             salvo_core::http::response::Parts {
                 status,
                 // version,
-                headers,
+                mut headers,
                 // extensions,
                 ..
             },
             body,
         ) = response.into_parts();
 
+        // Event streams are meant to stay open: only their idle timeout
+        // applies.
+        let deadline = deadline.filter(|_| !headers::is_event_stream(&headers));
+
+        headers::strip_hop_by_hop(&mut headers);
         res.set_status_code(status);
         res.set_headers(headers);
-        res.set_body(body.into());
+        res.set_body(
+            Body::wrap_stream(InFlightBody {
+                body,
+                _in_flight: in_flight,
+                total: deadline
+                    .zip(self.timeouts.total)
+                    .map(|(deadline, total)| (total, Box::pin(tokio::time::sleep_until(deadline)))),
+                idle: self
+                    .timeouts
+                    .idle_body
+                    .map(|idle| (idle, Box::pin(tokio::time::sleep(idle)))),
+                finished: false,
+            })
+            .into(),
+        );
 
         Ok(())
     }
+
+    // Forwards the switch of protocols to the client, then tunnels the two
+    // connections until they are closed or stay idle.
+    fn switch_protocols(
+        &self,
+        mut response: hyper::Response<Body>,
+        client: OnUpgrade,
+        upstream: &Upstream,
+        in_flight: InFlight,
+        res: &mut Response,
+    ) -> Result<(), Error> {
+        let idle_timeout = match &self.upgrade {
+            Some(upgrade) => upgrade.idle_timeout,
+            None => return self.respond(response, in_flight, None, res),
+        };
+        let upstream_upgrade = hyper::upgrade::on(&mut response);
+        let protocol = response.headers().get(header::UPGRADE).cloned();
+
+        let mut headers = std::mem::take(response.headers_mut());
+        headers::strip_hop_by_hop(&mut headers);
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        if let Some(protocol) = protocol {
+            headers.insert(header::UPGRADE, protocol);
+        }
+        res.set_status_code(StatusCode::SWITCHING_PROTOCOLS);
+        res.set_headers(headers);
+
+        let address = upstream.address.clone();
+        tokio::spawn(async move {
+            let (client, upstream) = match tokio::try_join!(client, upstream_upgrade) {
+                Ok(upgraded) => upgraded,
+                Err(e) => {
+                    tracing::warn!(target: "Proxy", address, error=e.to_string(), "failed to upgrade the connections");
+                    return;
+                }
+            };
+            tracing::debug!(target: "Proxy", address, "tunnel opened");
+            match tunnel(client, upstream, idle_timeout).await {
+                Ok(()) => tracing::debug!(target: "Proxy", address, "tunnel closed"),
+                Err(e) => {
+                    tracing::debug!(target: "Proxy", address, error=e.to_string(), "tunnel closed")
+                }
+            }
+            drop(in_flight);
+        });
+
+        Ok(())
+    }
+}
+
+// Copies the data between the client and the upstream, in both directions,
+// until both are closed. The tunnel is shut down when nothing is received for
+// the idle timeout.
+async fn tunnel<C, U>(mut client: C, mut upstream: U, idle_timeout: Duration) -> std::io::Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut client_buffer = vec![0u8; 8192];
+    let mut upstream_buffer = vec![0u8; 8192];
+    let (mut client_closed, mut upstream_closed) = (false, false);
+
+    while !(client_closed && upstream_closed) {
+        tokio::select! {
+            read = client.read(&mut client_buffer), if !client_closed => match read? {
+                0 => {
+                    client_closed = true;
+                    upstream.shutdown().await?;
+                }
+                n => upstream.write_all(&client_buffer[..n]).await?,
+            },
+            read = upstream.read(&mut upstream_buffer), if !upstream_closed => match read? {
+                0 => {
+                    upstream_closed = true;
+                    client.shutdown().await?;
+                }
+                n => client.write_all(&upstream_buffer[..n]).await?,
+            },
+            _ = tokio::time::sleep(idle_timeout) => {
+                return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "idle tunnel"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// The proxies, by rule name, in the order of the rules.
+type Proxies = Vec<(String, Arc<Proxy>)>;
+
+// The proxies of the rules, shared by the services of all the listeners so
+// that each upstream is probed once, and reported by the `health` action.
+#[derive(Clone, Default)]
+pub struct ProxyRegistry {
+    proxies: Arc<Mutex<Proxies>>,
+}
+
+impl ProxyRegistry {
+    pub fn get(&self, rule: &str) -> Option<Arc<Proxy>> {
+        self.proxies
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(name, _)| name == rule)
+            .map(|(_, proxy)| proxy.clone())
+    }
+
+    pub fn insert(&self, rule: &str, proxy: Arc<Proxy>) {
+        self.proxies.lock().unwrap().push((rule.to_string(), proxy));
+    }
+
+    pub fn start_health_checks(&self) {
+        for (_, proxy) in self.proxies.lock().unwrap().iter() {
+            proxy.start_health_checks();
+        }
+    }
+
+    pub fn status(&self) -> Vec<ProxyStatus> {
+        self.proxies
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(rule, proxy)| proxy.status(rule))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{
+        InFlight, Proxy, ProxyOptions, ProxyRegistry, Retry, Strategy, MAX_RETRY_ATTEMPTS,
+        MAX_RETRY_BACKOFF,
+    };
+    use crate::config::*;
+    use crate::routers;
+    use hyper::body::HttpBody;
+    use hyper::server::conn::AddrStream;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Server};
+    use salvo::http::StatusCode;
+    use salvo::prelude::{Request, Service};
+    use salvo::test::{ResponseExt, TestClient};
+    use std::collections::{HashMap, HashSet};
+    use std::convert::Infallible;
+    use std::future::Future;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Starts an upstream answering the requests with `handler`, and returns
+    // the number of TCP connections it has accepted so far.
+    fn spawn_upstream<H, F>(bind: &str, handler: H) -> Arc<AtomicUsize>
+    where
+        H: Fn(hyper::Request<Body>) -> F + Clone + Send + 'static,
+        F: Future<Output = hyper::Response<Body>> + Send + 'static,
+    {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+
+        let make_service = make_service_fn(move |_conn: &AddrStream| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let handler = handler.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let response = handler(req);
+                    async move { Ok::<_, Infallible>(response.await) }
+                }))
+            }
+        });
+
+        let server = Server::bind(&bind.parse().unwrap()).serve(make_service);
+        tokio::spawn(server);
+
+        connections
+    }
+
+    // Starts an upstream echoing back the request path and query, with its
+    // address in the `x-upstream` header, the Host header it received in
+    // `x-host`, the size of the request body in `x-body-size` and the HTTP
+    // version in `x-version`, and returns the number of TCP connections it has
+    // accepted so far.
+    pub(crate) fn upstream(bind: &str) -> Arc<AtomicUsize> {
+        let address = bind.to_string();
+        spawn_upstream(bind, move |req| {
+            let address = address.clone();
+            async move {
+                let path = req.uri().to_string();
+                let host = req.headers().get("host").cloned();
+                let version = format!("{:?}", req.version());
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                hyper::Response::builder()
+                    .header("x-upstream", address)
+                    .header("x-host", host.unwrap())
+                    .header("x-body-size", body.len())
+                    .header("x-version", version)
+                    .body(Body::from(path))
+                    .unwrap()
+            }
+        })
+    }
+
+    // Starts an upstream answering every request with `status`.
+    fn failing_upstream(bind: &str, status: StatusCode) {
+        spawn_upstream(bind, move |_req| async move {
+            hyper::Response::builder()
+                .status(status)
+                .body(Body::empty())
+                .unwrap()
+        });
+    }
+
+    // Starts an upstream waiting 500ms before sending its response headers,
+    // for the `/slow_headers` paths, or between the two chunks of its response
+    // body otherwise.
+    fn slow_upstream(bind: &str) {
+        spawn_upstream(bind, |req| async move {
+            let delay = Duration::from_millis(500);
+            if req.uri().path().ends_with("/slow_headers") {
+                tokio::time::sleep(delay).await;
+            }
+
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                sender.send_data("first".into()).await.ok();
+                tokio::time::sleep(delay).await;
+                sender.send_data("second".into()).await.ok();
+            });
+            hyper::Response::new(body)
+        });
+    }
+
+    // Starts an upstream switching to the protocol it is asked for, then
+    // echoing back what it receives. It answers `plain` otherwise.
+    fn upgrade_upstream(bind: &str) {
+        spawn_upstream(bind, |mut req| async move {
+            let protocol = match crate::headers::upgrade_protocol(req.headers()) {
+                Some(protocol) => protocol,
+                None => return hyper::Response::new(Body::from("plain")),
+            };
+
+            let on_upgrade = hyper::upgrade::on(&mut req);
+            tokio::spawn(async move {
+                let upgraded = on_upgrade.await.unwrap();
+                let (mut reader, mut writer) = tokio::io::split(upgraded);
+                tokio::io::copy(&mut reader, &mut writer).await.ok();
+            });
+            hyper::Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header("connection", "upgrade")
+                .header("upgrade", protocol)
+                .body(Body::empty())
+                .unwrap()
+        });
+    }
+
+    // Starts an upstream sending an event every 100ms: five of them, or as
+    // many as it can for the `/endless` path. It returns whether an endless
+    // stream has been cancelled.
+    fn event_upstream(bind: &str) -> Arc<AtomicBool> {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let state = cancelled.clone();
+
+        spawn_upstream(bind, move |req| {
+            let state = state.clone();
+            async move {
+                let count = match req.uri().path() {
+                    "/endless" => usize::MAX,
+                    _ => 5,
+                };
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    for i in 0..count {
+                        let event = format!("data: {}\n\n", i);
+                        if sender.send_data(event.into()).await.is_err() {
+                            state.store(true, Ordering::SeqCst);
+                            return;
+                        }
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                });
+                hyper::Response::builder()
+                    .header("content-type", "text/event-stream")
+                    .body(body)
+                    .unwrap()
+            }
+        });
+
+        cancelled
+    }
+
+    // Starts an upstream answering its health checks with 200 while `healthy`
+    // is set, and with 500 otherwise.
+    fn probed_upstream(bind: &str) -> Arc<AtomicBool> {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let state = healthy.clone();
+        let address = bind.to_string();
+
+        spawn_upstream(bind, move |req| {
+            let status = match req.uri().path() == "/health" && !state.load(Ordering::SeqCst) {
+                true => StatusCode::INTERNAL_SERVER_ERROR,
+                false => StatusCode::OK,
+            };
+            let address = address.clone();
+            async move {
+                hyper::Response::builder()
+                    .status(status)
+                    .header("x-upstream", address)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        });
+
+        healthy
+    }
+
+    #[tokio::test]
+    async fn test_proxy_pool() {
+        let pooled = upstream("127.0.0.1:5901");
+        let unpooled = upstream("127.0.0.1:5902");
+
+        let config = Config::create_from_filename("tests/configs/005_proxy_pool.yaml").unwrap();
+        let service = Service::new(routers::routers(&config.rules, &config.server).unwrap());
+
+        for _ in 0..3 {
+            let mut resp = TestClient::get("http://127.0.0.1:5800/pooled/path")
+                .send(&service)
+                .await;
+            assert_eq!(resp.status_code().unwrap(), StatusCode::OK);
+            assert_eq!(resp.take_string().await.unwrap(), "/pooled/path");
+        }
+        assert_eq!(pooled.load(Ordering::SeqCst), 1);
+
+        for _ in 0..3 {
+            let mut resp = TestClient::get("http://127.0.0.1:5800/unpooled/path")
+                .send(&service)
+                .await;
+            assert_eq!(resp.status_code().unwrap(), StatusCode::OK);
+            assert_eq!(resp.take_string().await.unwrap(), "/unpooled/path");
+        }
+        assert_eq!(unpooled.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_client_body() {
+        upstream("127.0.0.1:5928");
+
+        let config = Config::create_from_filename("tests/configs/028_client_body.yaml").unwrap();
+        let service = Service::new(routers::routers(&config.rules, &config.server).unwrap());
+
+        let resp = TestClient::post("http://127.0.0.1:5800/upload/path")
+            .body("body")
+            .send(&service)
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::OK);
+        assert_eq!(resp.headers()["x-body-size"], "4");
+
+        // The client fails to send its body: this is not the upstream's fault.
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            sender.send_data("first".into()).await.ok();
+            sender.abort();
+        });
+        let resp = TestClient::post("http://127.0.0.1:5800/upload/path")
+            .body(body)
+            .send(&service)
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_load_balancing() {
+        upstream("127.0.0.1:5903");
+        upstream("127.0.0.1:5904");
+
+        let config = Config::create_from_filename("tests/configs/017_load_balancing.yaml").unwrap();
+        let service = Service::new(routers::routers(&config.rules, &config.server).unwrap());
+
+        let mut served = HashMap::new();
+        for _ in 0..6 {
+            let resp = TestClient::get("http://127.0.0.1:5800/round_robin/path")
+                .send(&service)
+                .await;
+            assert_eq!(resp.status_code().unwrap(), StatusCode::OK);
+            *served
+                .entry(resp.headers()["x-upstream"].to_str().unwrap().to_string())
+                .or_insert(0) += 1;
+        }
+        assert_eq!(served["127.0.0.1:5903"], 4);
+        assert_eq!(served["127.0.0.1:5904"], 2);
+
+        let mut served = HashMap::new();
+        for i in 0..20 {
+            let user = format!("user{}", i % 10);
+            let resp = TestClient::get("http://127.0.0.1:5800/hash/path")
+                .add_header("x-user", &user, true)
+                .send(&service)
+                .await;
+            let upstream = resp.headers()["x-upstream"].to_str().unwrap().to_string();
+            // The same user always goes to the same upstream.
+            assert_eq!(served.entry(user).or_insert(upstream.clone()), &upstream);
+        }
+        assert_eq!(served.values().collect::<HashSet<_>>().len(), 2);
+    }
+
+    #[test]
+    fn test_proxy_select() {
+        let upstreams = [("http://127.0.0.1:1", 1), ("http://127.0.0.1:2", 1)];
+        let req: Request = hyper::Request::builder()
+            .body(Body::empty())
+            .unwrap()
+            .into();
+
+        let proxy = Proxy::create(
+            &upstreams,
+            ProxyOptions {
+                strategy: Strategy::LeastConnections,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let _in_flight = InFlight::new(&proxy.upstreams[0].in_flight);
+        for _ in 0..4 {
+            assert_eq!(proxy.select(&req, &[]), 1);
+        }
+        // Retries go to another upstream.
+        assert_eq!(proxy.select(&req, &[1]), 0);
+
+        let proxy = Proxy::create(
+            &upstreams,
+            ProxyOptions {
+                strategy: Strategy::Random,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let selected: HashSet<usize> = (0..100).map(|_| proxy.select(&req, &[])).collect();
+        assert_eq!(selected.len(), 2);
+
+        // Reporting the target of a dry run leaves the rotation where it is.
+        let proxy = Proxy::create(&upstreams, Default::default()).unwrap();
+        for _ in 0..2 {
+            assert_eq!(proxy.target("/a"), "http://127.0.0.1:1/a");
+        }
+        assert_eq!(proxy.select(&req, &[]), 0);
+        assert_eq!(proxy.select(&req, &[]), 1);
+
+        let balance = ConfigRuleBalance {
+            strategy: Some("sticky".to_string()),
+            header: None,
+        };
+        assert!(Strategy::parse(&balance).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_passive_health_check() {
+        upstream("127.0.0.1:5905");
+
+        let config = Config::create_from_filename("tests/configs/018_health_check.yaml").unwrap();
+        let service = Service::new(routers::routers(&config.rules, &config.server).unwrap());
+
+        // 127.0.0.1:5906 refuses the connections: it is ejected after two
+        // failures.
+        let mut failures = 0;
+        for _ in 0..10 {
+            let resp = TestClient::get("http://127.0.0.1:5800/passive/path")
+                .send(&service)
+                .await;
+            match resp.status_code().unwrap() {
+                StatusCode::OK => assert_eq!(resp.headers()["x-upstream"], "127.0.0.1:5905"),
+                status => {
+                    assert_eq!(status, StatusCode::BAD_GATEWAY);
+                    failures += 1;
+                }
+            }
+        }
+        assert_eq!(failures, 2);
+    }
+
+    // The upstreams serving four requests.
+    async fn served(service: &Service, url: &str) -> HashSet<String> {
+        let mut served = HashSet::new();
+        for _ in 0..4 {
+            let resp = TestClient::get(url).send(service).await;
+            assert_eq!(resp.status_code().unwrap(), StatusCode::OK);
+            served.insert(resp.headers()["x-upstream"].to_str().unwrap().to_string());
+        }
+        served
+    }
+
+    // Polls the registry until the probes report `address` as `healthy`.
+    async fn wait_for_health(registry: &ProxyRegistry, address: &str, healthy: bool) {
+        let mut reported = None;
+        for _ in 0..50 {
+            reported = registry
+                .status()
+                .into_iter()
+                .flat_map(|proxy| proxy.upstreams)
+                .find(|upstream| upstream.address == address)
+                .map(|upstream| upstream.healthy);
+            if reported == Some(healthy) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(reported, Some(healthy));
+    }
+
+    #[tokio::test]
+    async fn test_active_health_check() {
+        upstream("127.0.0.1:5908");
+        let healthy = probed_upstream("127.0.0.1:5907");
+        healthy.store(false, Ordering::SeqCst);
+
+        let config = Config::create_from_filename("tests/configs/018_health_check.yaml").unwrap();
+        let registry = ProxyRegistry::default();
+        let service = Service::new(
+            routers::routers_with_registry(&config.rules, &config.server, &registry).unwrap(),
+        );
+        registry.start_health_checks();
+        wait_for_health(&registry, "127.0.0.1:5907", false).await;
+
+        assert_eq!(
+            served(&service, "http://127.0.0.1:5800/active/path").await,
+            HashSet::from(["127.0.0.1:5908".to_string()])
+        );
+
+        let mut resp = TestClient::get("http://127.0.0.1:5800/_health")
+            .send(&service)
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::OK);
+        let status = resp.take_string().await.unwrap();
+        assert!(status.contains(r#"{"address":"127.0.0.1:5907","weight":1,"healthy":false"#));
+        assert!(status.contains(r#"{"address":"127.0.0.1:5908","weight":1,"healthy":true"#));
+
+        // The next probe, a second later, succeeds.
+        healthy.store(true, Ordering::SeqCst);
+        wait_for_health(&registry, "127.0.0.1:5907", true).await;
+        assert_eq!(
+            served(&service, "http://127.0.0.1:5800/active/path")
+                .await
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_retry() {
+        upstream("127.0.0.1:5914");
+        failing_upstream("127.0.0.1:5916", StatusCode::BAD_GATEWAY);
+
+        let config = Config::create_from_filename("tests/configs/019_retry.yaml").unwrap();
+        let service = Service::new(routers::routers(&config.rules, &config.server).unwrap());
+
+        // 127.0.0.1:5915 refuses the connections.
+        for _ in 0..4 {
+            let resp = TestClient::get("http://127.0.0.1:5800/connect/path")
+                .send(&service)
+                .await;
+            assert_eq!(resp.status_code().unwrap(), StatusCode::OK);
+            assert_eq!(resp.headers()["x-upstream"], "127.0.0.1:5914");
+        }
+
+        // The body is replayed to the second upstream.
+        for _ in 0..4 {
+            let resp = TestClient::put("http://127.0.0.1:5800/status/path")
+                .body("body")
+                .send(&service)
+                .await;
+            assert_eq!(resp.status_code().unwrap(), StatusCode::OK);
+            assert_eq!(resp.headers()["x-body-size"], "4");
+        }
+
+        // POST is not idempotent, and larger bodies are not buffered: one of
+        // two consecutive requests reaches the failing upstream.
+        let mut statuses = HashSet::new();
+        for _ in 0..2 {
+            let resp = TestClient::post("http://127.0.0.1:5800/status/path")
+                .send(&service)
+                .await;
+            statuses.insert(resp.status_code().unwrap());
+        }
+        assert_eq!(
+            statuses,
+            HashSet::from([StatusCode::OK, StatusCode::BAD_GATEWAY])
+        );
+
+        let mut statuses = HashSet::new();
+        for _ in 0..2 {
+            let resp = TestClient::put("http://127.0.0.1:5800/status/path")
+                .body("a larger body")
+                .send(&service)
+                .await;
+            statuses.insert(resp.status_code().unwrap());
+        }
+        assert_eq!(
+            statuses,
+            HashSet::from([StatusCode::OK, StatusCode::BAD_GATEWAY])
+        );
+
+        // The backoff would outlast the total timeout: the request fails at
+        // once instead of retrying too late.
+        let start = std::time::Instant::now();
+        let resp = TestClient::get("http://127.0.0.1:5800/deadline/path")
+            .send(&service)
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let retry = Retry::parse(&ConfigRuleRetry {
+            attempts: Some(MAX_RETRY_ATTEMPTS),
+            backoff: Some(u64::MAX / 2),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(retry.delay(1), MAX_RETRY_BACKOFF);
+        assert_eq!(retry.delay(MAX_RETRY_ATTEMPTS), MAX_RETRY_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_timeouts() {
+        slow_upstream("127.0.0.1:5917");
+
+        let config = Config::create_from_filename("tests/configs/020_timeouts.yaml").unwrap();
+        let service = Service::new(routers::routers(&config.rules, &config.server).unwrap());
+
+        let resp = TestClient::get("http://127.0.0.1:5800/slow_headers/slow_headers")
+            .send(&service)
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::GATEWAY_TIMEOUT);
+
+        let mut resp = TestClient::get("http://127.0.0.1:5800/patient/slow_headers")
+            .send(&service)
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::OK);
+        assert_eq!(resp.take_string().await.unwrap(), "firstsecond");
+
+        // The headers have been sent: the body is cut.
+        for path in ["slow_body", "total"] {
+            let mut resp = TestClient::get(format!("http://127.0.0.1:5800/{}/path", path))
+                .send(&service)
+                .await;
+            assert_eq!(resp.status_code().unwrap(), StatusCode::OK);
+            assert!(resp.take_string().await.is_err());
+        }
+    }
+
+    // Sends a raw HTTP/1.1 request asking to switch to `echo`, and returns the
+    // connection with the response headers.
+    async fn upgrade_request(address: &str, path: &str) -> (tokio::net::TcpStream, String) {
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: mozilla.social\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            assert_eq!(stream.read(&mut byte).await.unwrap(), 1);
+            head.push(byte[0]);
+        }
+        (stream, String::from_utf8(head).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_proxy_upgrade() {
+        upgrade_upstream("127.0.0.1:5921");
+
+        let config = Config::create_from_filename("tests/configs/024_upgrade.yaml").unwrap();
+        let services =
+            crate::server::create_services(&config.server, &config.rules, &Default::default())
+                .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:5920")
+            .await
+            .unwrap();
+        let service = crate::server::SharedService::new(services.into_iter().next().unwrap());
+        tokio::spawn(crate::server::serve(listener, None, service, None));
+
+        let (mut stream, head) = upgrade_request("127.0.0.1:5920", "/streaming/api").await;
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        assert!(head.to_lowercase().contains("upgrade: echo"), "{}", head);
+
+        let mut echoed = [0u8; 5];
+        stream.write_all(b"hello").await.unwrap();
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"hello");
+
+        // The tunnel is closed once idle.
+        let closed = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut echoed)).await;
+        assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))));
+
+        // Without `upgrade.allow`, the upstream never sees the upgrade.
+        let (_, head) = upgrade_request("127.0.0.1:5920", "/plain/api").await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_event_stream() {
+        let cancelled = event_upstream("127.0.0.1:5923");
+
+        let config = Config::create_from_filename("tests/configs/025_event_stream.yaml").unwrap();
+        let services =
+            crate::server::create_services(&config.server, &config.rules, &Default::default())
+                .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:5922")
+            .await
+            .unwrap();
+        let service = crate::server::SharedService::new(services.into_iter().next().unwrap());
+        tokio::spawn(crate::server::serve(listener, None, service, None));
+
+        // Each event is received on its own, and the total timeout does not cut
+        // the stream.
+        let client = hyper::Client::new();
+        let uri = "http://127.0.0.1:5922/events/stream".parse().unwrap();
+        let mut body = client.get(uri).await.unwrap().into_body();
+        let mut events = Vec::new();
+        while let Some(chunk) = body.data().await {
+            events.push(String::from_utf8(chunk.unwrap().to_vec()).unwrap());
+        }
+        let expected: Vec<String> = (0..5).map(|i| format!("data: {}\n\n", i)).collect();
+        assert_eq!(events, expected);
+
+        // The upstream response is cancelled with the one of the client.
+        let uri = "http://127.0.0.1:5922/events/endless".parse().unwrap();
+        let mut body = client.get(uri).await.unwrap().into_body();
+        assert_eq!(body.data().await.unwrap().unwrap(), "data: 0\n\n");
+        drop(body);
+        for _ in 0..20 {
+            if cancelled.load(Ordering::SeqCst) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(cancelled.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_protocol() {
+        let connections = upstream("127.0.0.1:5924");
+
+        let config =
+            Config::create_from_filename("tests/configs/026_upstream_protocol.yaml").unwrap();
+        let service = Service::new(routers::routers(&config.rules, &config.server).unwrap());
+
+        // Concurrent requests share a single HTTP/2 connection.
+        let request = || {
+            TestClient::get("http://127.0.0.1:5800/h2c/path")
+                .add_header("host", "mozilla.social", true)
+                .send(&service)
+        };
+        let responses = tokio::join!(request(), request(), request(), request());
+        for resp in [responses.0, responses.1, responses.2, responses.3] {
+            assert_eq!(resp.status_code().unwrap(), StatusCode::OK);
+            assert_eq!(resp.headers()["x-version"], "HTTP/2.0");
+            assert_eq!(resp.headers()["x-host"], "mozilla.social");
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
 }
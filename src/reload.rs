@@ -0,0 +1,252 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::config::{Config, ConfigServer};
+use crate::errors::Error;
+use crate::proxy::ProxyRegistry;
+use crate::server::{self, SharedService};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+// Editors and deployment tools often write a file in several steps. We wait a
+// bit after the first change before reading the file again.
+const RELOAD_DELAY: Duration = Duration::from_millis(200);
+
+// The listeners keep running: the new rules are applied to the `server` section
+// the server has been started with. The previous proxies stop their health
+// checks once their requests in flight complete.
+pub fn reload(
+    filename: &str,
+    server: &ConfigServer,
+    services: &[SharedService],
+) -> Result<(), Error> {
+    let config = Config::create_from_filename(filename)?;
+    let registry = ProxyRegistry::default();
+    let new_services = server::create_services(server, &config.rules, &registry)?;
+    registry.start_health_checks();
+
+    if &config.server != server {
+        tracing::warn!(target: "Reload", filename=filename, "changes to the `server` section require a restart");
+    }
+
+    for (service, new_service) in services.iter().zip(new_services) {
+        service.replace(new_service);
+    }
+    Ok(())
+}
+
+// Watches some files, sending a message each time one of them changes. The
+// watcher stops when dropped.
+pub fn watch_files(
+    filenames: &[&str],
+) -> notify::Result<(RecommendedWatcher, UnboundedReceiver<()>)> {
+    let mut paths = Vec::new();
+    for filename in filenames {
+        paths.push(Path::new(filename).canonicalize()?);
+    }
+
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    let watched_paths = paths.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                && event.paths.iter().any(|p| watched_paths.contains(p))
+            {
+                let _ = tx.send(());
+            }
+        }
+    })?;
+
+    // We watch the directories, so that files replaced by a rename are still
+    // detected.
+    let mut directories: Vec<PathBuf> = paths
+        .iter()
+        .filter_map(|path| path.parent().map(PathBuf::from))
+        .collect();
+    directories.sort();
+    directories.dedup();
+
+    for directory in directories {
+        watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+    }
+
+    Ok((watcher, rx))
+}
+
+// Waits for the next change of the watched files. Returns `false` when the
+// watcher is gone.
+pub async fn changed(rx: &mut UnboundedReceiver<()>) -> bool {
+    if rx.recv().await.is_none() {
+        return false;
+    }
+
+    tokio::time::sleep(RELOAD_DELAY).await;
+    while rx.try_recv().is_ok() {}
+    true
+}
+
+// Waits for the next change of the configuration file. Returns `false` when it
+// is not watched, or no longer.
+async fn file_changed(rx: &mut Option<UnboundedReceiver<()>>) -> bool {
+    match rx {
+        Some(rx) => changed(rx).await,
+        None => false,
+    }
+}
+
+// Waits for the next SIGHUP. Returns `false` when it cannot be received.
+async fn hangup_received(hangup: &mut Option<Signal>) -> bool {
+    match hangup {
+        Some(hangup) => hangup.recv().await.is_some(),
+        None => false,
+    }
+}
+
+// Reloads the configuration when its file changes or when SIGHUP is received.
+// Each source works without the other: the reloads stop once both are gone.
+pub fn watch(config: &Config, services: Vec<SharedService>) {
+    let filename = config.filename.clone();
+    let server = config.server.clone();
+
+    let (watcher, mut rx) = match watch_files(&[&filename]) {
+        Ok((watcher, rx)) => {
+            tracing::info!(target: "Reload", filename=filename, "watching the configuration file");
+            (Some(watcher), Some(rx))
+        }
+        Err(e) => {
+            tracing::error!(target: "Reload", filename=filename, error=e.to_string(), "unable to watch the configuration file");
+            (None, None)
+        }
+    };
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(e) => {
+            tracing::error!(target: "Reload", error=e.to_string(), "unable to listen for SIGHUP");
+            None
+        }
+    };
+
+    tokio::spawn(async move {
+        let _watcher = watcher;
+
+        loop {
+            tokio::select! {
+                changed = file_changed(&mut rx), if rx.is_some() => {
+                    if !changed {
+                        tracing::error!(target: "Reload", filename=filename, "the configuration file is no longer watched");
+                        rx = None;
+                        continue;
+                    }
+                    tracing::info!(target: "Reload", filename=filename, "configuration file changed");
+                }
+                received = hangup_received(&mut hangup), if hangup.is_some() => {
+                    if !received {
+                        hangup = None;
+                        continue;
+                    }
+                    tracing::info!(target: "Reload", filename=filename, "SIGHUP received");
+                }
+                else => break,
+            }
+
+            match reload(&filename, &server, &services) {
+                Ok(()) => {
+                    tracing::info!(target: "Reload", filename=filename, "configuration reloaded")
+                }
+                Err(Error::InvalidConfig(report)) => {
+                    report.log(&filename);
+                    tracing::error!(target: "Reload", filename=filename, "invalid configuration, keeping the previous rules")
+                }
+                Err(e) => {
+                    tracing::error!(target: "Reload", filename=filename, error=e.to_string(), "invalid configuration, keeping the previous rules")
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use crate::server::{self, SharedService};
+    use salvo::http::StatusCode;
+    use salvo::test::TestClient;
+    use std::time::Duration;
+
+    fn copy_config(from: &str, to: &std::path::Path) {
+        std::fs::copy(from, to).unwrap();
+    }
+
+    fn shared_services(config: &Config) -> Vec<SharedService> {
+        server::create_services(&config.server, &config.rules, &Default::default())
+            .unwrap()
+            .into_iter()
+            .map(SharedService::new)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let filename = std::env::temp_dir().join("social-routing-test-reload.yaml");
+        copy_config("tests/configs/001_filter_path.yaml", &filename);
+
+        let config = Config::create_from_filename(filename.to_str().unwrap()).unwrap();
+        let services = shared_services(&config);
+        let service = services[0].clone();
+
+        let resp = TestClient::get("http://127.0.0.1:5800/test1")
+            .send(&*service.current())
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
+
+        // An invalid configuration keeps the previous rules.
+        copy_config("tests/configs/006_invalid_action.yaml", &filename);
+        assert!(super::reload(filename.to_str().unwrap(), &config.server, &services).is_err());
+
+        let resp = TestClient::get("http://127.0.0.1:5800/test1")
+            .send(&*service.current())
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
+
+        copy_config("tests/configs/002_filter_method.yaml", &filename);
+        assert!(super::reload(filename.to_str().unwrap(), &config.server, &services).is_ok());
+
+        let resp = TestClient::delete("http://127.0.0.1:5800/test1")
+            .send(&*service.current())
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(resp.headers()["location"], "test_delete");
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let filename = std::env::temp_dir().join("social-routing-test-watch.yaml");
+        copy_config("tests/configs/001_filter_path.yaml", &filename);
+
+        let config = Config::create_from_filename(filename.to_str().unwrap()).unwrap();
+        let services = shared_services(&config);
+        let service = services[0].clone();
+        super::watch(&config, services);
+
+        copy_config("tests/configs/002_filter_method.yaml", &filename);
+
+        let mut status = None;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let resp = TestClient::delete("http://127.0.0.1:5800/whatever")
+                .send(&*service.current())
+                .await;
+            status = resp.status_code();
+            if status == Some(StatusCode::TEMPORARY_REDIRECT) {
+                break;
+            }
+        }
+        assert_eq!(status.unwrap(), StatusCode::TEMPORARY_REDIRECT);
+    }
+}
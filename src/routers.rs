@@ -5,62 +5,354 @@
 use crate::action::*;
 use crate::condition::*;
 use crate::config::*;
+use crate::proxy::ProxyRegistry;
+use crate::validation::{Issue, Report};
 use http::Method;
 use salvo::prelude::*;
-use salvo::routing::{MethodFilter, PathFilter};
+use salvo::routing::{Filter, PathFilter};
+use std::collections::HashSet;
 
-fn create_route(rule: &ConfigRule) -> Router {
+// Salvo panics on invalid path patterns: they are checked first, and reported
+// as issues instead.
+fn path_filter(path: &str) -> Result<PathFilter, String> {
+    check_path(path).map_err(|e| format!("invalid path `{}`: {}", path, e))?;
+    Ok(PathFilter::new(path))
+}
+
+// A path is made of segments of text, each one optionally ended by a `<name>`
+// parameter. Only the last segment can capture the rest of the path, with
+// `<*name>` or `<**name>`.
+fn check_path(path: &str) -> Result<(), String> {
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+    for (i, segment) in segments.iter().enumerate() {
+        let last = i == segments.len() - 1;
+        if segment.is_empty() {
+            if last {
+                continue;
+            }
+            return Err("empty segment".to_string());
+        }
+
+        let (text, parameter) = match segment.find('<') {
+            Some(start) => (&segment[..start], Some(&segment[start + 1..])),
+            None => (*segment, None),
+        };
+        if let Some(c) = text.chars().find(|c| ":<>[]()".contains(*c)) {
+            return Err(format!("unexpected `{}`", c));
+        }
+
+        let Some(parameter) = parameter else {
+            continue;
+        };
+        let name = match parameter.find('>') {
+            Some(end) if end == parameter.len() - 1 => &parameter[..end],
+            Some(_) => return Err("a parameter must end its segment".to_string()),
+            None => return Err("unclosed `<`".to_string()),
+        };
+        let stars = name.len() - name.trim_start_matches('*').len();
+        if stars > 2 || (stars == 0 && name.is_empty()) {
+            return Err(format!("invalid parameter `<{}>`", name));
+        }
+        if stars > 0 && !last {
+            return Err(format!("`<{}>` must be in the last segment", name));
+        }
+        if !name[stars..]
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(format!("invalid parameter name `{}`", &name[stars..]));
+        }
+    }
+
+    Ok(())
+}
+
+fn method_condition(
+    method: &ConfigRuleMethod,
+    server: &ConfigServer,
+) -> Result<ConditionMethod, String> {
+    let (names, negate) = match method {
+        ConfigRuleMethod::One(name) => (std::slice::from_ref(name), false),
+        ConfigRuleMethod::List(names) => (names.as_slice(), false),
+        ConfigRuleMethod::Not { not } => (not.as_slice(), true),
+    };
+
+    if names.is_empty() {
+        return Err("no methods".to_string());
+    }
+
+    let methods = names
+        .iter()
+        .map(|name| {
+            Method::from_bytes(name.as_bytes()).map_err(|_| format!("invalid method `{}`", name))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(ConditionMethod::new(
+        methods,
+        negate,
+        server.implicit_head.unwrap_or(false),
+    ))
+}
+
+// The value condition of a header or query parameter.
+fn value_match(
+    value: &Option<String>,
+    mode: &Option<String>,
+) -> Result<Option<ValueMatch>, String> {
+    match (value.as_deref(), mode.as_deref()) {
+        (Some(value), mode) => ValueMatch::new(mode, value).map(Some),
+        (None, Some(_)) => Err("`match` requires a value".to_string()),
+        (None, None) => Ok(None),
+    }
+}
+
+fn header_condition(header: &ConfigRuleHeader) -> Result<ConditionHeader, String> {
+    let value = value_match(&header.value, &header.match_mode)
+        .map_err(|e| format!("header `{}`: {}", header.name, e))?;
+    Ok(ConditionHeader::new(
+        &header.name,
+        value,
+        header.negate.unwrap_or(false),
+    ))
+}
+
+fn query_condition(query: &ConfigRuleQuery) -> Result<ConditionQuery, String> {
+    let value = value_match(&query.value, &query.match_mode)
+        .map_err(|e| format!("query parameter `{}`: {}", query.name, e))?;
+    Ok(ConditionQuery::new(
+        &query.name,
+        value,
+        query.negate.unwrap_or(false),
+    ))
+}
+
+fn accept_condition(accept: &ConfigRuleAccept) -> Result<ConditionAccept, String> {
+    ConditionAccept::new(&accept.types, accept.preferred.unwrap_or(false))
+}
+
+fn match_conditions(
+    blocks: &[ConfigMatch],
+    server: &ConfigServer,
+    combinator: &str,
+) -> Result<Vec<Box<dyn Filter>>, String> {
+    if blocks.is_empty() {
+        return Err(format!("empty `{}`", combinator));
+    }
+
+    blocks
+        .iter()
+        .map(|block| match_condition(block, server).map(|f| Box::new(f) as Box<dyn Filter>))
+        .collect()
+}
+
+// Compiles a `match` block: all of its conditions must match.
+fn match_condition(block: &ConfigMatch, server: &ConfigServer) -> Result<ConditionMatch, String> {
+    let mut filters: Vec<Box<dyn Filter>> = Vec::new();
+
+    if let Some(method) = &block.method {
+        filters.push(Box::new(method_condition(method, server)?));
+    }
+    if let Some(host) = block.host.as_deref() {
+        filters.push(Box::new(ConditionHost::new(HostPattern::parse(host)?)));
+    }
+    if let Some(path) = block.path.as_deref() {
+        filters.push(Box::new(ConditionMatch::Path(path_filter(path)?)));
+    }
+    if let Some(header) = &block.header {
+        filters.push(Box::new(header_condition(header)?));
+    }
+    if let Some(query) = &block.query {
+        filters.push(Box::new(query_condition(query)?));
+    }
+    if let Some(accept) = &block.accept {
+        filters.push(Box::new(accept_condition(accept)?));
+    }
+    if let Some(all) = &block.all {
+        filters.push(Box::new(ConditionMatch::All(match_conditions(
+            all, server, "all",
+        )?)));
+    }
+    if let Some(any) = &block.any {
+        filters.push(Box::new(ConditionMatch::Any(match_conditions(
+            any, server, "any",
+        )?)));
+    }
+    if let Some(not) = &block.not {
+        filters.push(Box::new(ConditionMatch::Not(Box::new(match_condition(
+            not, server,
+        )?))));
+    }
+
+    if filters.is_empty() {
+        return Err("empty condition".to_string());
+    }
+
+    Ok(ConditionMatch::All(filters))
+}
+
+pub fn create_route(
+    rule: &ConfigRule,
+    server: &ConfigServer,
+    registry: &ProxyRegistry,
+) -> Result<Router, Report> {
     tracing::info!(target: "Routing", rule=rule.name, "creating route");
 
+    let mut report = Report::new();
     let mut router = Router::new();
 
-    if let Some(method) = rule.method.as_deref() {
-        let method = Method::from_bytes(method.as_bytes());
-        if method.is_err() {
-            tracing::error!(target: "Routing", rule=rule.name, method=rule.method, "invalid method");
-            std::process::exit(1);
+    if let Some(method) = &rule.method {
+        match method_condition(method, server) {
+            Ok(condition) => {
+                tracing::info!(target: "Routing", rule=rule.name, method=?condition, "filtering method");
+                router = router.filter(condition);
+            }
+            Err(e) => report.push(Issue::rule(rule, "method", e)),
         }
+    }
 
-        tracing::info!(target: "Routing", rule=rule.name, method=rule.method, "filtering method");
-        router = router.filter(MethodFilter(method.unwrap()));
+    if let Some(host) = rule.host.as_deref() {
+        match HostPattern::parse(host) {
+            Ok(pattern) => {
+                tracing::info!(target: "Routing", rule=rule.name, host=host, "filtering host");
+                router = router.filter(ConditionHost::new(pattern));
+            }
+            Err(e) => report.push(Issue::rule(rule, "host", e)),
+        }
     }
 
     if let Some(path) = rule.path.as_deref() {
-        if path == "/" || path == "" {
+        if path == "/" || path.is_empty() {
             tracing::info!(target: "Routing", rule=rule.name, "filtering path index");
         } else {
             tracing::info!(target: "Routing", rule=rule.name, path=path, "filtering path");
-            router = router.filter(PathFilter::new(path));
+            match path_filter(path) {
+                Ok(filter) => router = router.filter(filter),
+                Err(e) => report.push(Issue::rule(rule, "path", e)),
+            }
         }
     } else {
         router = router.filter(PathFilter::new("<**>"));
     }
 
-    if rule.headers.is_some() {
-        for header in rule.headers.as_ref().unwrap() {
-            router = router.filter(ConditionHeader::new(&header.name, header.value.as_deref()));
+    for header in rule.headers.iter().flatten() {
+        match header_condition(header) {
+            Ok(condition) => router = router.filter(condition),
+            Err(e) => report.push(Issue::rule(rule, "headers", e)),
+        }
+    }
+
+    for query in rule.query.iter().flatten() {
+        match query_condition(query) {
+            Ok(condition) => router = router.filter(condition),
+            Err(e) => report.push(Issue::rule(rule, "query", e)),
+        }
+    }
+
+    if let Some(accept) = &rule.accept {
+        match accept_condition(accept) {
+            Ok(condition) => router = router.filter(condition),
+            Err(e) => report.push(Issue::rule(rule, "accept", e)),
+        }
+    }
+
+    if let Some(block) = &rule.matches {
+        match match_condition(block, server) {
+            Ok(condition) => {
+                tracing::info!(target: "Routing", rule=rule.name, condition=?condition, "filtering match block");
+                router = router.filter(condition);
+            }
+            Err(e) => report.push(Issue::rule(rule, "match", e)),
         }
     }
 
     tracing::info!(target: "Routing", rule=rule.name, action=rule.action, "handling action");
-    match rule.action.as_str() {
-        "redirect" => router.handle(RedirectAction::new(rule)),
-        "proxy" => router.handle(ProxyAction::new(rule)),
-        _ => {
-            tracing::error!(target: "Routing", rule=rule.name, action=rule.action, "invalid action");
-            std::process::exit(1);
+    let action = match rule.action.as_str() {
+        "redirect" => RedirectAction::new(rule).map(|action| router.handle(action)),
+        "proxy" => ProxyAction::new(rule, server, registry).map(|action| router.handle(action)),
+        "health" => Ok(router.handle(HealthAction::new(rule, registry))),
+        _ => Err(Issue::rule(rule, "action", format!("invalid action `{}`", rule.action)).into()),
+    };
+
+    match action {
+        Ok(router) => report.check(router),
+        Err(action_report) => {
+            report.merge(action_report);
+            Err(report)
         }
     }
 }
 
-pub fn routers(rules: &Vec<ConfigRule>) -> Router {
-    let mut router = Router::new();
+// Builds the routers of all the rules, reporting every invalid rule at once.
+// The services build the routes of their listener instead (see
+// `server::create_routes`).
+#[cfg(test)]
+pub fn routers<'a>(
+    rules: impl IntoIterator<Item = &'a ConfigRule>,
+    server: &ConfigServer,
+) -> Result<Router, Report> {
+    routers_with_registry(rules, server, &ProxyRegistry::default())
+}
+
+// Builds the routers of the rules, sharing the proxies of the registry.
+#[cfg(test)]
+pub fn routers_with_registry<'a>(
+    rules: impl IntoIterator<Item = &'a ConfigRule>,
+    server: &ConfigServer,
+    registry: &ProxyRegistry,
+) -> Result<Router, Report> {
+    Ok(create_routes(rules, server, |_| registry)?
+        .into_iter()
+        .fold(Router::new(), Router::push))
+}
+
+// Builds the route of each rule, in order, sharing its proxy through the
+// registry given for the rule.
+pub fn create_routes<'a, 'r>(
+    rules: impl IntoIterator<Item = &'a ConfigRule>,
+    server: &ConfigServer,
+    registry: impl Fn(&ConfigRule) -> &'r ProxyRegistry,
+) -> Result<Vec<Router>, Report> {
+    let mut report = Report::new();
+    let mut routes = Vec::new();
+    let mut names = HashSet::new();
 
     for rule in rules {
-        router = router.push(create_route(rule));
+        if !names.insert(rule.name.as_str()) {
+            report.push(Issue::rule(rule, "name", "duplicate rule name"));
+        }
+
+        match create_route(rule, server, registry(rule)) {
+            Ok(route) => routes.push(route),
+            Err(rule_report) => report.merge(rule_report),
+        }
+    }
+
+    report.check(routes)
+}
+
+// The rules served by a listener. Selectors matching no rule are reported, as
+// they are likely typos.
+pub fn listener_rules<'a>(
+    listener: &ConfigListener,
+    rules: &'a [ConfigRule],
+) -> Result<Vec<&'a ConfigRule>, Report> {
+    let mut report = Report::new();
+
+    for selector in listener.rules.iter().flatten() {
+        if !rules.iter().any(|rule| rule.is_selected_by(selector)) {
+            report.push(Issue::new(
+                format!(
+                    "listener `{}`: no rule named or tagged `{}`",
+                    listener.bind, selector
+                ),
+                None,
+            ));
+        }
     }
 
-    router
+    report.check(rules.iter().filter(|rule| listener.selects(rule)).collect())
 }
 
 #[cfg(test)]
@@ -71,55 +363,89 @@ mod tests {
 
     #[tokio::test]
     async fn test_filter_path() {
-        let config = Config::create_from_filename("tests/configs/001_filter_path.yaml");
+        let config = Config::create_from_filename("tests/configs/001_filter_path.yaml").unwrap();
 
-        let resp = TestClient::get(format!("http://127.0.0.1:5800/notfound"))
-            .send(super::routers(&config.rules))
+        let resp = TestClient::get("http://127.0.0.1:5800/notfound")
+            .send(super::routers(&config.rules, &config.server).unwrap())
             .await;
         assert_eq!(resp.status_code().unwrap(), StatusCode::NOT_FOUND);
 
-        let resp = TestClient::get(format!("http://127.0.0.1:5800/test1"))
-            .send(super::routers(&config.rules))
+        let resp = TestClient::get("http://127.0.0.1:5800/test1")
+            .send(super::routers(&config.rules, &config.server).unwrap())
             .await;
         assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
         assert!(resp.headers().contains_key("location"));
         assert_eq!(resp.headers()["location"], "test1");
 
-        let resp = TestClient::post(format!("http://127.0.0.1:5800/test2/with/path"))
-            .send(super::routers(&config.rules))
+        let resp = TestClient::post("http://127.0.0.1:5800/test2/with/path")
+            .send(super::routers(&config.rules, &config.server).unwrap())
             .await;
         assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
         assert!(resp.headers().contains_key("location"));
         assert_eq!(resp.headers()["location"], "test2");
 
-        let resp = TestClient::put(format!("http://127.0.0.1:5800/test3/a/path/b"))
-            .send(super::routers(&config.rules))
+        let resp = TestClient::put("http://127.0.0.1:5800/test3/a/path/b")
+            .send(super::routers(&config.rules, &config.server).unwrap())
             .await;
         assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
         assert!(resp.headers().contains_key("location"));
         assert_eq!(resp.headers()["location"], "test3");
     }
 
+    #[test]
+    fn test_check_path() {
+        // The accepted paths never make salvo panic.
+        for path in [
+            "",
+            "/",
+            "home",
+            "/health/",
+            "@<user>",
+            "test2/<id>/b/<name>",
+            "users/<**>",
+            "elk/<**rest>",
+            "a/<*rest>",
+        ] {
+            assert!(super::check_path(path).is_ok(), "{}", path);
+            salvo::routing::PathFilter::new(path);
+        }
+
+        for path in [
+            "test7/<id",
+            "a/<>",
+            "a/<id>b",
+            "a/<**rest>/b",
+            "a/<***rest>",
+            "a/<id:num>",
+            "a/<i d>",
+            "a//b",
+            "a/b>",
+            "a/(b)",
+        ] {
+            assert!(super::check_path(path).is_err(), "{}", path);
+        }
+    }
+
     #[tokio::test]
     async fn test_filter_method() {
-        let config = Config::create_from_filename("tests/configs/002_filter_method.yaml");
+        let config = Config::create_from_filename("tests/configs/002_filter_method.yaml").unwrap();
 
-        let resp = TestClient::post(format!("http://127.0.0.1:5800/whatever"))
-            .send(super::routers(&config.rules))
+        let resp = TestClient::post("http://127.0.0.1:5800/whatever")
+            .send(super::routers(&config.rules, &config.server).unwrap())
             .await;
         assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
         assert!(resp.headers().contains_key("location"));
         assert_eq!(resp.headers()["location"], "test_post");
 
-        let resp = TestClient::get(format!("http://127.0.0.1:5800/whatever"))
-            .send(super::routers(&config.rules))
+        let resp = TestClient::get("http://127.0.0.1:5800/whatever")
+            .send(super::routers(&config.rules, &config.server).unwrap())
             .await;
         assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
         assert!(resp.headers().contains_key("location"));
         assert_eq!(resp.headers()["location"], "test_get");
 
-        let resp = TestClient::delete(format!("http://127.0.0.1:5800/whatever"))
-            .send(super::routers(&config.rules))
+        let resp = TestClient::delete("http://127.0.0.1:5800/whatever")
+            .send(super::routers(&config.rules, &config.server).unwrap())
             .await;
         assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
         assert!(resp.headers().contains_key("location"));
@@ -128,45 +454,305 @@ mod tests {
 
     #[tokio::test]
     async fn test_filter_header_condition() {
-        let config = Config::create_from_filename("tests/configs/003_filter_header_condition.yaml");
+        let config =
+            Config::create_from_filename("tests/configs/003_filter_header_condition.yaml").unwrap();
 
-        let resp = TestClient::get(format!("http://127.0.0.1:5800/whatever"))
+        let resp = TestClient::get("http://127.0.0.1:5800/whatever")
             .add_header("Content-Type", "foo", true)
-            .send(super::routers(&config.rules))
+            .send(super::routers(&config.rules, &config.server).unwrap())
             .await;
         assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
         assert!(resp.headers().contains_key("location"));
         assert_eq!(resp.headers()["location"], "test1");
 
-        let resp = TestClient::get(format!("http://127.0.0.1:5800/whatever"))
+        let resp = TestClient::get("http://127.0.0.1:5800/whatever")
             .add_header("content-type", "bar", true)
-            .send(super::routers(&config.rules))
+            .send(super::routers(&config.rules, &config.server).unwrap())
             .await;
         assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
         assert!(resp.headers().contains_key("location"));
         assert_eq!(resp.headers()["location"], "test2");
 
-        let resp = TestClient::get(format!("http://127.0.0.1:5800/whatever"))
+        let resp = TestClient::get("http://127.0.0.1:5800/whatever")
             .add_header("foobar", "any value", true)
-            .send(super::routers(&config.rules))
+            .send(super::routers(&config.rules, &config.server).unwrap())
             .await;
         assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
         assert!(resp.headers().contains_key("location"));
         assert_eq!(resp.headers()["location"], "test3");
 
-        let resp = TestClient::get(format!("http://127.0.0.1:5800/whatever"))
+        let resp = TestClient::get("http://127.0.0.1:5800/whatever")
             .add_header("foo", "any value", true)
             .add_header("bar", "abc", true)
-            .send(super::routers(&config.rules))
+            .send(super::routers(&config.rules, &config.server).unwrap())
             .await;
         assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
         assert!(resp.headers().contains_key("location"));
         assert_eq!(resp.headers()["location"], "test4");
 
-        let resp = TestClient::get(format!("http://127.0.0.1:5800/whatever"))
+        let resp = TestClient::get("http://127.0.0.1:5800/whatever")
             .add_header("foo", "any value", true)
             .add_header("bar", "cba", true)
-            .send(super::routers(&config.rules))
+            .send(super::routers(&config.rules, &config.server).unwrap())
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_filter_host_condition() {
+        let config = Config::create_from_filename("tests/configs/011_host_condition.yaml").unwrap();
+
+        for (host, location) in [
+            ("mozilla.social", "https://mozilla.social/about"),
+            ("MOZILLA.social:8000", "https://mozilla.social/about"),
+            (
+                "a.staging.mozilla.social",
+                "https://staging.mozilla.social/about",
+            ),
+            (
+                "a.b.staging.mozilla.social",
+                "https://staging.mozilla.social/about",
+            ),
+            (
+                "alice.mozilla.social",
+                "https://mozilla.social/@alice/about",
+            ),
+        ] {
+            let resp = TestClient::get("http://127.0.0.1:5800/about")
+                .add_header("host", host, true)
+                .send(super::routers(&config.rules, &config.server).unwrap())
+                .await;
+            assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
+            assert_eq!(resp.headers()["location"], location);
+        }
+
+        for host in ["staging.mozilla.social.example", "example.com"] {
+            let resp = TestClient::get("http://127.0.0.1:5800/about")
+                .add_header("host", host, true)
+                .send(super::routers(&config.rules, &config.server).unwrap())
+                .await;
+            assert_eq!(resp.status_code().unwrap(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_header_match() {
+        let config = Config::create_from_filename("tests/configs/012_header_match.yaml").unwrap();
+
+        for (path, name, value, matched) in [
+            ("ignore_case", "accept", "application/json", true),
+            ("ignore_case", "accept", "application/jsonx", false),
+            ("prefix", "accept", "application/ld+json", true),
+            ("prefix", "accept", "text/html", false),
+            (
+                "contains",
+                "accept",
+                "text/html, application/activity+json",
+                true,
+            ),
+            ("contains", "accept", "application/json", false),
+            ("regex", "content-type", "application/activity+json", true),
+            (
+                "regex",
+                "content-type",
+                "application/ld+json;profile=\"https://www.w3.org/ns/activitystreams\"",
+                true,
+            ),
+            (
+                "regex",
+                "content-type",
+                "application/ld+json ; profile=\"https://www.w3.org/ns/activitystreams\"",
+                true,
+            ),
+            ("regex", "content-type", "application/json", false),
+            ("absent", "accept", "text/html", true),
+            ("absent", "authorization", "Bearer abc", false),
+            ("not_matching", "accept", "application/json", true),
+            ("not_matching", "accept", "text/html", false),
+        ] {
+            let resp = TestClient::get(format!("http://127.0.0.1:5800/{}", path))
+                .add_header(name, value, true)
+                .send(super::routers(&config.rules, &config.server).unwrap())
+                .await;
+            if matched {
+                assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
+                assert_eq!(resp.headers()["location"], path);
+            } else {
+                assert_eq!(resp.status_code().unwrap(), StatusCode::NOT_FOUND);
+            }
+        }
+
+        // A missing header does not match, unless negated.
+        let resp = TestClient::get("http://127.0.0.1:5800/not_matching")
+            .send(super::routers(&config.rules, &config.server).unwrap())
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
+    }
+
+    #[tokio::test]
+    async fn test_filter_accept_condition() {
+        let config =
+            Config::create_from_filename("tests/configs/013_accept_condition.yaml").unwrap();
+
+        for (accept, location) in [
+            (
+                Some("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+                Some("elk"),
+            ),
+            (Some("application/activity+json"), Some("mastodon")),
+            (
+                Some("application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\""),
+                Some("mastodon"),
+            ),
+            (
+                Some("application/activity+json;q=0.5, text/html;q=0.9"),
+                Some("elk"),
+            ),
+            (Some("application/*, text/html;q=0.1"), Some("mastodon")),
+            (
+                Some("application/ld+json; profile=\"https://example.com\", text/html;q=0.5"),
+                Some("elk"),
+            ),
+            (Some("application/json"), None),
+            (Some("text/html;q=0, application/json"), None),
+            (Some("*/*"), Some("elk")),
+            (None, Some("elk")),
+        ] {
+            let mut client = TestClient::get("http://127.0.0.1:5800/@alice");
+            if let Some(accept) = accept {
+                client = client.add_header("accept", accept, true);
+            }
+            let resp = client
+                .send(super::routers(&config.rules, &config.server).unwrap())
+                .await;
+            match location {
+                Some(location) => {
+                    assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
+                    assert_eq!(resp.headers()["location"], location);
+                }
+                None => assert_eq!(resp.status_code().unwrap(), StatusCode::NOT_FOUND),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_query_condition() {
+        let config =
+            Config::create_from_filename("tests/configs/014_query_condition.yaml").unwrap();
+
+        for (query, location) in [
+            ("q=alice&type=accounts", Some("/accounts?q=alice")),
+            ("type=accounts&q=a%20b%26c", Some("/accounts?q=a+b%26c")),
+            (
+                "q=rust&type=hashtags",
+                Some("/statuses?q=rust&type=hashtags"),
+            ),
+            ("q=rust", Some("/search")),
+            ("type=accounts", None),
+            ("q=rust&type=other", None),
+        ] {
+            let resp = TestClient::get(format!("http://127.0.0.1:5800/search?{}", query))
+                .send(super::routers(&config.rules, &config.server).unwrap())
+                .await;
+            match location {
+                Some(location) => {
+                    assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
+                    assert_eq!(resp.headers()["location"], location);
+                }
+                None => assert_eq!(resp.status_code().unwrap(), StatusCode::NOT_FOUND),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_match_block() {
+        let config = Config::create_from_filename("tests/configs/015_match_block.yaml").unwrap();
+
+        for (method, url, header, location) in [
+            (
+                "GET",
+                "/whatever",
+                Some(("content-type", "application/activity+json")),
+                Some("activitypub"),
+            ),
+            (
+                "POST",
+                "/inbox",
+                Some(("accept", "text/html, application/ld+json")),
+                Some("activitypub"),
+            ),
+            ("GET", "/api/v1/timelines", None, Some("api")),
+            ("GET", "/oauth/authorize", None, Some("api")),
+            ("POST", "/api/v1/timelines", None, None),
+            ("GET", "/api/health", None, None),
+            ("GET", "/search?q=rust", None, Some("search")),
+            (
+                "GET",
+                "/search",
+                Some(("host", "a.staging.example.com")),
+                Some("search"),
+            ),
+            ("GET", "/search", None, None),
+        ] {
+            let url = format!("http://127.0.0.1:5800{}", url);
+            let mut client = match method {
+                "GET" => TestClient::get(url),
+                _ => TestClient::post(url),
+            };
+            if let Some((name, value)) = header {
+                client = client.add_header(name, value, true);
+            }
+            let resp = client
+                .send(super::routers(&config.rules, &config.server).unwrap())
+                .await;
+            match location {
+                Some(location) => {
+                    assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
+                    assert_eq!(resp.headers()["location"], location);
+                }
+                None => assert_eq!(resp.status_code().unwrap(), StatusCode::NOT_FOUND),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_methods() {
+        let config = Config::create_from_filename("tests/configs/016_methods.yaml").unwrap();
+        let router = || super::routers(&config.rules, &config.server).unwrap();
+
+        for (client, location) in [
+            (TestClient::get("http://127.0.0.1:5800/list"), Some("read")),
+            (
+                TestClient::options("http://127.0.0.1:5800/list"),
+                Some("read"),
+            ),
+            (TestClient::head("http://127.0.0.1:5800/list"), Some("read")),
+            (TestClient::post("http://127.0.0.1:5800/list"), None),
+            (
+                TestClient::get("http://127.0.0.1:5800/item"),
+                Some("not_post"),
+            ),
+            (
+                TestClient::delete("http://127.0.0.1:5800/item"),
+                Some("not_post"),
+            ),
+            (TestClient::post("http://127.0.0.1:5800/item"), Some("post")),
+        ] {
+            let resp = client.send(router()).await;
+            match location {
+                Some(location) => {
+                    assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
+                    assert_eq!(resp.headers()["location"], location);
+                }
+                None => assert_eq!(resp.status_code().unwrap(), StatusCode::NOT_FOUND),
+            }
+        }
+
+        // HEAD requests are only served by GET rules when configured.
+        let mut server = config.server.clone();
+        server.implicit_head = None;
+        let resp = TestClient::head("http://127.0.0.1:5800/list")
+            .send(super::routers(&config.rules, &server).unwrap())
             .await;
         assert_eq!(resp.status_code().unwrap(), StatusCode::NOT_FOUND);
     }
@@ -4,17 +4,535 @@
 
 use crate::catchers;
 use crate::config;
+use crate::headers::TlsConnection;
+use crate::http3;
+use crate::proxy::ProxyRegistry;
+use crate::reload;
 use crate::routers;
+use crate::tls;
+use crate::validation::{Issue, Report};
+use http::header::{self, HeaderValue};
+use hyper::server::conn::Http;
+use hyper::service::{service_fn, Service as _};
 use salvo::logging::Logger;
-use salvo::prelude::{Server, Service, TcpListener};
+use salvo::prelude::{Router, Service};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+// How long to wait before accepting connections again after a failure.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+// The service currently used to handle new requests. Requests in flight keep
+// the service they started with, so that replacing it never interrupts them.
+#[derive(Clone)]
+pub struct SharedService {
+    current: Arc<RwLock<Arc<Service>>>,
+}
+
+impl SharedService {
+    pub fn new(service: Service) -> SharedService {
+        SharedService {
+            current: Arc::new(RwLock::new(Arc::new(service))),
+        }
+    }
+
+    pub fn current(&self) -> Arc<Service> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, service: Service) {
+        *self.current.write().unwrap() = Arc::new(service);
+    }
+}
+
+// The routes of the rules, built and validated in one pass, and the rules
+// served by each listener.
+pub struct Routes<'a> {
+    server: &'a config::ConfigServer,
+    registry: &'a ProxyRegistry,
+    rules: &'a [config::ConfigRule],
+    routes: Vec<Option<Router>>,
+    listeners: Vec<Vec<&'a config::ConfigRule>>,
+    setups: Vec<ListenerSetup>,
+}
+
+impl<'a> Routes<'a> {
+    // The rules served by each listener, in the order of the listeners.
+    pub fn listeners(&self) -> Vec<Vec<&'a config::ConfigRule>> {
+        self.listeners.clone()
+    }
+
+    // Takes the route of a rule. A rule served by several listeners needs a
+    // route each: the next ones are built again, with the proxy of the first.
+    pub fn take(&mut self, rule: &config::ConfigRule) -> Result<Router, Report> {
+        let index = self
+            .rules
+            .iter()
+            .position(|r| std::ptr::eq(r, rule))
+            .expect("a rule of the configuration");
+
+        match self.routes[index].take() {
+            Some(route) => Ok(route),
+            None => routers::create_route(rule, self.server, self.registry),
+        }
+    }
+}
+
+// Validates the listeners and builds the route of every rule, even the ones no
+// listener serves. Only the proxies of the served rules join the registry.
+pub fn create_routes<'a>(
+    server: &'a config::ConfigServer,
+    rules: &'a [config::ConfigRule],
+    registry: &'a ProxyRegistry,
+) -> Result<Routes<'a>, Report> {
+    let mut report = Report::new();
+
+    let listeners = server.listeners();
+    if listeners.is_empty() {
+        report.push(Issue::new(
+            "no listeners: `server.bind` or `server.listeners` is required",
+            None,
+        ));
+    }
+
+    let mut selections = Vec::new();
+    let mut setups = Vec::new();
+    for listener in &listeners {
+        match listener_setup(listener) {
+            Ok(setup) => setups.push(setup),
+            Err(listener_report) => report.merge(listener_report),
+        }
+        match routers::listener_rules(listener, rules) {
+            Ok(selection) => selections.push(selection),
+            Err(listener_report) => report.merge(listener_report),
+        }
+    }
+
+    let unserved = ProxyRegistry::default();
+    let routes = routers::create_routes(rules, server, |rule| {
+        if listeners.iter().any(|listener| listener.selects(rule)) {
+            registry
+        } else {
+            &unserved
+        }
+    });
+
+    let routes = match routes {
+        Ok(routes) => routes.into_iter().map(Some).collect(),
+        Err(rules_report) => {
+            report.merge(rules_report);
+            Vec::new()
+        }
+    };
+
+    report.check(Routes {
+        server,
+        registry,
+        rules,
+        routes,
+        listeners: selections,
+        setups,
+    })
+}
+
+// Creates the service of each listener, with its setup. Every rule is
+// validated, even the ones no listener serves. The proxies are shared through
+// the registry.
+fn create_listeners(
+    server: &config::ConfigServer,
+    rules: &[config::ConfigRule],
+    registry: &ProxyRegistry,
+) -> Result<Vec<(ListenerSetup, Service)>, Report> {
+    let mut routes = create_routes(server, rules, registry)?;
+    let setups = std::mem::take(&mut routes.setups);
+
+    let mut listeners = Vec::new();
+    for (selection, setup) in routes.listeners().into_iter().zip(setups) {
+        let mut router = Router::new();
+        for rule in selection {
+            router = router.push(routes.take(rule)?);
+        }
+        let service = Service::new(router.hoop(Logger)).with_catchers(catchers::catchers());
+        listeners.push((setup, service));
+    }
+    Ok(listeners)
+}
+
+// Creates the service of each listener, as `serve` would.
+pub fn create_services(
+    server: &config::ConfigServer,
+    rules: &[config::ConfigRule],
+    registry: &ProxyRegistry,
+) -> Result<Vec<Service>, Report> {
+    Ok(create_listeners(server, rules, registry)?
+        .into_iter()
+        .map(|(_, service)| service)
+        .collect())
+}
+
+// Serves the HTTP connections accepted by `listener`, terminating TLS first
+// when an acceptor is given. The responses advertise the HTTP/3 listener with
+// `alt_svc`, if any.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    service: SharedService,
+    alt_svc: Option<HeaderValue>,
+) {
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Such errors, as running out of file descriptors, usually
+                // persist for a while: retrying at once would spin.
+                tracing::error!(target: "Service", error=e.to_string(), "unable to accept a connection");
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+        let alt_svc = alt_svc.clone();
+        tokio::spawn(async move {
+            let tls = acceptor.is_some();
+            let handler = service_fn(move |mut req: hyper::Request<hyper::Body>| {
+                if tls {
+                    req.extensions_mut().insert(TlsConnection);
+                }
+                let response = service
+                    .current()
+                    .hyper_handler(Some(remote_addr.into()))
+                    .call(req);
+                let alt_svc = alt_svc.clone();
+                async move {
+                    let mut response = response.await?;
+                    if let Some(alt_svc) = alt_svc {
+                        response.headers_mut().insert(header::ALT_SVC, alt_svc);
+                    }
+                    Ok::<_, hyper::Error>(response)
+                }
+            });
+
+            let result = match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        Http::new()
+                            .serve_connection(stream, handler)
+                            .with_upgrades()
+                            .await
+                    }
+                    Err(e) => {
+                        tracing::debug!(target: "Service", remote_addr=remote_addr.to_string(), error=e.to_string(), "TLS handshake failed");
+                        return;
+                    }
+                },
+                None => {
+                    Http::new()
+                        .serve_connection(stream, handler)
+                        .with_upgrades()
+                        .await
+                }
+            };
+
+            if let Err(e) = result {
+                tracing::debug!(target: "Service", remote_addr=remote_addr.to_string(), error=e.to_string(), "connection error");
+            }
+        });
+    }
+}
+
+// The addresses and the TLS configuration of a listener. They are checked with
+// the rules, so that `check` rejects the configurations `serve` cannot start
+// with. Only binding the sockets can still fail.
+struct ListenerSetup {
+    bind: String,
+    addr: SocketAddr,
+    tls: Option<(Arc<tls::CertificateStore>, TlsAcceptor)>,
+    // The UDP address of the HTTP/3 listener, its QUIC configuration and the
+    // `Alt-Svc` value advertising it.
+    http3: Option<(SocketAddr, quinn::ServerConfig, HeaderValue)>,
+}
+
+fn socket_addr(
+    listener: &config::ConfigListener,
+    field: &str,
+    bind: &str,
+) -> Result<SocketAddr, Issue> {
+    bind.to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| Issue::listener(listener, field, format!("invalid address `{}`", bind)))
+}
+
+fn listener_setup(listener: &config::ConfigListener) -> Result<ListenerSetup, Report> {
+    let mut report = Report::new();
+
+    let addr = match socket_addr(listener, "bind", &listener.bind) {
+        Ok(addr) => Some(addr),
+        Err(issue) => {
+            report.push(issue);
+            None
+        }
+    };
+
+    let store = match &listener.tls {
+        Some(tls) => match tls::CertificateStore::new(tls) {
+            Ok(store) => Some(Arc::new(store)),
+            Err(e) => {
+                report.push(Issue::listener(
+                    listener,
+                    &format!("tls.{}", e.field),
+                    e.reason,
+                ));
+                None
+            }
+        },
+        None => None,
+    };
+
+    let tls = store
+        .clone()
+        .and_then(|store| match tls::acceptor(store.clone()) {
+            Ok(acceptor) => Some((store, acceptor)),
+            Err(e) => {
+                report.push(Issue::listener(listener, "tls", e));
+                None
+            }
+        });
+
+    let http3 = match (&listener.http3, store) {
+        (Some(_), None) if listener.tls.is_none() => {
+            report.push(Issue::listener(listener, "http3", "requires `tls`"));
+            None
+        }
+        (Some(http3), Some(store)) => {
+            let bind = http3.bind.as_deref().unwrap_or(&listener.bind);
+            let addr = socket_addr(listener, "http3.bind", bind);
+            let quic_config =
+                tls::quic_config(store).map_err(|e| Issue::listener(listener, "http3", e));
+            match (addr, quic_config) {
+                (Ok(addr), Ok(quic_config)) => {
+                    let alt_svc = format!(
+                        "h3=\":{}\"; ma={}",
+                        addr.port(),
+                        http3.max_age.unwrap_or(86400)
+                    );
+                    Some((addr, quic_config, HeaderValue::from_str(&alt_svc).unwrap()))
+                }
+                (addr, quic_config) => {
+                    for issue in addr.err().into_iter().chain(quic_config.err()) {
+                        report.push(issue);
+                    }
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    report.check(())?;
+    Ok(ListenerSetup {
+        bind: listener.bind.clone(),
+        addr: addr.expect("a checked address"),
+        tls,
+        http3,
+    })
+}
+
+fn quic_endpoint(
+    bind: &str,
+    addr: SocketAddr,
+    quic_config: quinn::ServerConfig,
+) -> quinn::Endpoint {
+    match quinn::Endpoint::server(quic_config, addr) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            tracing::error!(target: "Service", binding=bind, error=e.to_string(), "unable to bind the HTTP/3 server");
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn bind(bind: &str, addr: SocketAddr) -> TcpListener {
+    match TcpListener::bind(addr).await {
+        Ok(tcp_listener) => tcp_listener,
+        Err(e) => {
+            tracing::error!(target: "Service", binding=bind, error=e.to_string(), "unable to bind the server");
+            std::process::exit(1);
+        }
+    }
+}
 
 pub async fn run(config: &config::Config) {
-    let service = Service::new(routers::routers(&config.rules).hoop(Logger))
-        .with_catchers(catchers::catchers());
+    let registry = ProxyRegistry::default();
+    let (setups, services): (Vec<ListenerSetup>, Vec<SharedService>) =
+        match create_listeners(&config.server, &config.rules, &registry) {
+            Ok(listeners) => {
+                registry.start_health_checks();
+                listeners
+                    .into_iter()
+                    .map(|(setup, service)| (setup, SharedService::new(service)))
+                    .unzip()
+            }
+            Err(report) => {
+                report.log(&config.filename);
+                tracing::error!(target: "Service", "unable to create the service");
+                std::process::exit(1);
+            }
+        };
+
+    reload::watch(config, services.clone());
+
+    let mut servers = Vec::new();
+    for (setup, service) in setups.into_iter().zip(services) {
+        let acceptor = setup.tls.map(|(store, acceptor)| {
+            store.watch();
+            acceptor
+        });
+
+        let alt_svc = setup.http3.map(|(addr, quic_config, alt_svc)| {
+            let endpoint = quic_endpoint(&setup.bind, addr, quic_config);
+            tracing::info!(target: "Service", binding=setup.bind, alt_svc=?alt_svc, "binding the HTTP/3 server");
+            servers.push(tokio::spawn(http3::serve(endpoint, service.clone())));
+            alt_svc
+        });
+
+        tracing::info!(target: "Service", binding=setup.bind, tls=acceptor.is_some(), "binding the server");
+        let tcp_listener = bind(&setup.bind, setup.addr).await;
+        servers.push(tokio::spawn(serve(
+            tcp_listener,
+            acceptor,
+            service,
+            alt_svc,
+        )));
+    }
+
+    for server in servers {
+        let _ = server.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use crate::proxy::ProxyRegistry;
+    use salvo::http::StatusCode;
+    use salvo::test::TestClient;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_create_services() {
+        let config = Config::create_from_filename("tests/configs/010_listeners.yaml").unwrap();
+        let services =
+            super::create_services(&config.server, &config.rules, &Default::default()).unwrap();
+        assert_eq!(services.len(), 2);
+
+        for (path, public, admin) in [
+            (
+                "home",
+                StatusCode::TEMPORARY_REDIRECT,
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                "profile/42",
+                StatusCode::TEMPORARY_REDIRECT,
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                "admin",
+                StatusCode::NOT_FOUND,
+                StatusCode::TEMPORARY_REDIRECT,
+            ),
+        ] {
+            let url = format!("http://127.0.0.1:5800/{}", path);
+            let resp = TestClient::get(&url).send(&services[0]).await;
+            assert_eq!(resp.status_code().unwrap(), public);
+            let resp = TestClient::get(&url).send(&services[1]).await;
+            assert_eq!(resp.status_code().unwrap(), admin);
+        }
+
+        // The legacy `bind` is a listener serving every rule.
+        let mut server = config.server.clone();
+        server.bind = Some("127.0.0.1:5913".to_string());
+        let services = super::create_services(&server, &config.rules, &Default::default()).unwrap();
+        assert_eq!(services.len(), 3);
+        let resp = TestClient::get("http://127.0.0.1:5800/admin")
+            .send(&services[0])
+            .await;
+        assert_eq!(resp.status_code().unwrap(), StatusCode::TEMPORARY_REDIRECT);
+
+        let mut server = config.server.clone();
+        server.listeners.as_mut().unwrap()[1].rules = Some(vec!["nope".to_string()]);
+        match super::create_services(&server, &config.rules, &Default::default()) {
+            Err(report) => assert_eq!(report.issues.len(), 1),
+            Ok(_) => panic!("the selector should be reported"),
+        }
+
+        server.listeners = None;
+        match super::create_services(&server, &config.rules, &Default::default()) {
+            Err(report) => assert_eq!(report.issues.len(), 1),
+            Ok(_) => panic!("the missing listeners should be reported"),
+        }
+
+        // The certificates and the addresses are checked with the rules.
+        let config = Config::create_from_filename("tests/configs/009_tls.yaml").unwrap();
+        let mut server = config.server.clone();
+        server.bind = Some("nowhere".to_string());
+        server.tls.as_mut().unwrap().certificates[1].key = "tests/certs/missing.key".to_string();
+        let fields: Vec<_> =
+            match super::create_services(&server, &config.rules, &Default::default()) {
+                Err(report) => report
+                    .issues
+                    .into_iter()
+                    .map(|i| i.field.unwrap())
+                    .collect(),
+                Ok(_) => panic!("the listener should be reported"),
+            };
+        assert_eq!(fields, ["bind", "tls.certificates[1].key"]);
+
+        // The proxy of a rule is built once, whatever the number of listeners
+        // serving it. The rules no listener serves stay out of the registry.
+        let config = Config::create_from_filename("tests/configs/005_proxy_pool.yaml").unwrap();
+        let mut server = config.server.clone();
+        server.bind = None;
+        server.listeners = Some(
+            ["127.0.0.1:8001", "127.0.0.1:8002"]
+                .map(|bind| ConfigListener {
+                    bind: bind.to_string(),
+                    tls: None,
+                    http3: None,
+                    rules: Some(vec!["pooled proxy".to_string()]),
+                })
+                .to_vec(),
+        );
+        let registry = ProxyRegistry::default();
+        let services = super::create_services(&server, &config.rules, &registry).unwrap();
+        assert_eq!(services.len(), 2);
+        assert_eq!(registry.status().len(), 1);
+    }
 
-    tracing::info!(target: "Service", binding=config.server.bind, "binding the server");
+    #[tokio::test]
+    async fn test_run_listeners() {
+        let config = Config::create_from_filename("tests/configs/010_listeners.yaml").unwrap();
+        tokio::spawn(async move { super::run(&config).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
 
-    Server::new(TcpListener::bind(&config.server.bind))
-        .serve(service)
-        .await;
+        let client = hyper::Client::new();
+        for (url, status) in [
+            ("http://127.0.0.1:5911/home", StatusCode::TEMPORARY_REDIRECT),
+            ("http://127.0.0.1:5911/admin", StatusCode::NOT_FOUND),
+            (
+                "http://127.0.0.1:5912/admin",
+                StatusCode::TEMPORARY_REDIRECT,
+            ),
+            ("http://127.0.0.1:5912/home", StatusCode::NOT_FOUND),
+        ] {
+            let resp = client.get(url.parse().unwrap()).await.unwrap();
+            assert_eq!(resp.status(), status);
+        }
+    }
 }
@@ -0,0 +1,399 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::config::{ConfigTls, ConfigTlsCertificate};
+use crate::reload;
+use quinn::crypto::rustls::QuicServerConfig;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::version::TLS13;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+#[derive(Debug, Default)]
+struct Certificates {
+    default: Option<Arc<CertifiedKey>>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+// Selects the certificate by the SNI name sent by the client. The
+// certificates can be replaced while the server runs.
+#[derive(Debug)]
+pub struct CertificateStore {
+    config: ConfigTls,
+    certificates: RwLock<Arc<Certificates>>,
+}
+
+pub fn load_certs(filename: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = std::fs::File::open(filename)
+        .map_err(|e| format!("unable to read `{}`: {}", filename, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid certificate `{}`: {}", filename, e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate in `{}`", filename));
+    }
+    Ok(certs)
+}
+
+pub fn load_private_key(filename: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = std::fs::File::open(filename)
+        .map_err(|e| format!("unable to read `{}`: {}", filename, e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("invalid private key `{}`: {}", filename, e))?
+        .ok_or_else(|| format!("no private key in `{}`", filename))
+}
+
+// A certificate that cannot be loaded, with the field of the `tls` section
+// naming the faulty file, such as `certificates[0].key`.
+#[derive(Debug)]
+pub struct CertificateError {
+    pub field: String,
+    pub reason: String,
+}
+
+impl Display for CertificateError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "`{}`: {}", self.field, self.reason)
+    }
+}
+
+fn load_certificate(
+    index: usize,
+    certificate: &ConfigTlsCertificate,
+) -> Result<CertifiedKey, CertificateError> {
+    let error = |file: &'static str| {
+        move |reason| CertificateError {
+            field: format!("certificates[{}].{}", index, file),
+            reason,
+        }
+    };
+
+    let chain = load_certs(&certificate.cert).map_err(error("cert"))?;
+    let key = load_private_key(&certificate.key).map_err(error("key"))?;
+    let key = ring::sign::any_supported_type(&key)
+        .map_err(|e| format!("unsupported private key `{}`: {}", certificate.key, e))
+        .map_err(error("key"))?;
+
+    let certified_key = CertifiedKey::new(chain, key);
+    certified_key
+        .keys_match()
+        .map_err(|e| format!("`{}` and `{}`: {}", certificate.cert, certificate.key, e))
+        .map_err(error("key"))?;
+    Ok(certified_key)
+}
+
+fn load_certificates(config: &ConfigTls) -> Result<Certificates, CertificateError> {
+    let mut certificates = Certificates::default();
+
+    for (index, certificate) in config.certificates.iter().enumerate() {
+        let certified_key = Arc::new(load_certificate(index, certificate)?);
+
+        if certificates.default.is_none() {
+            certificates.default = Some(certified_key.clone());
+        }
+
+        for name in certificate.server_names.iter().flatten() {
+            certificates
+                .by_name
+                .insert(name.to_lowercase(), certified_key.clone());
+        }
+    }
+
+    if certificates.default.is_none() {
+        return Err(CertificateError {
+            field: "certificates".to_string(),
+            reason: "no certificates".to_string(),
+        });
+    }
+
+    Ok(certificates)
+}
+
+impl CertificateStore {
+    pub fn new(config: &ConfigTls) -> Result<CertificateStore, CertificateError> {
+        Ok(CertificateStore {
+            config: config.clone(),
+            certificates: RwLock::new(Arc::new(load_certificates(config)?)),
+        })
+    }
+
+    // Loads the certificates from disk again. On errors, the current ones are
+    // kept.
+    pub fn reload(&self) -> Result<(), CertificateError> {
+        let certificates = load_certificates(&self.config)?;
+        *self.certificates.write().unwrap() = Arc::new(certificates);
+        Ok(())
+    }
+
+    pub fn certificate_for(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.certificates.read().unwrap().clone();
+
+        if let Some(name) = server_name.map(|n| n.to_lowercase()) {
+            if let Some(certificate) = certificates.by_name.get(&name) {
+                return Some(certificate.clone());
+            }
+
+            if let Some((_, parent)) = name.split_once('.') {
+                if let Some(certificate) = certificates.by_name.get(&format!("*.{}", parent)) {
+                    return Some(certificate.clone());
+                }
+            }
+        }
+
+        certificates.default.clone()
+    }
+
+    // Reloads the certificates when their files change.
+    pub fn watch(self: &Arc<Self>) {
+        let filenames: Vec<&str> = self
+            .config
+            .certificates
+            .iter()
+            .flat_map(|c| [c.cert.as_str(), c.key.as_str()])
+            .collect();
+
+        let (watcher, mut rx) = match reload::watch_files(&filenames) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                tracing::error!(target: "Tls", error=e.to_string(), "unable to watch the certificates");
+                return;
+            }
+        };
+
+        let store = self.clone();
+        tokio::spawn(async move {
+            let _watcher = watcher;
+
+            while reload::changed(&mut rx).await {
+                match store.reload() {
+                    Ok(()) => tracing::info!(target: "Tls", "certificates reloaded"),
+                    Err(e) => {
+                        tracing::error!(target: "Tls", error=e.to_string(), "invalid certificates, keeping the previous ones")
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertificateStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.certificate_for(client_hello.server_name())
+    }
+}
+
+pub fn acceptor(store: Arc<CertificateStore>) -> Result<TlsAcceptor, String> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_cert_resolver(store);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// The QUIC configuration of the HTTP/3 listeners, sharing the certificates of
+// their TCP listener. QUIC requires TLS 1.3.
+pub fn quic_config(store: Arc<CertificateStore>) -> Result<quinn::ServerConfig, String> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_protocol_versions(&[&TLS13])
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_cert_resolver(store);
+    config.alpn_protocols = vec![b"h3".to_vec()];
+
+    let crypto = QuicServerConfig::try_from(config).map_err(|e| e.to_string())?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::config::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::sign::CertifiedKey;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    fn leaf(certificate: &CertifiedKey) -> Vec<u8> {
+        certificate.cert[0].to_vec()
+    }
+
+    fn pem(filename: &str) -> Vec<u8> {
+        let file = std::fs::File::open(filename).unwrap();
+        rustls_pemfile::certs(&mut std::io::BufReader::new(file))
+            .next()
+            .unwrap()
+            .unwrap()
+            .to_vec()
+    }
+
+    fn config(directory: &str) -> ConfigTls {
+        ConfigTls {
+            certificates: vec![
+                ConfigTlsCertificate {
+                    cert: format!("{}/localhost.pem", directory),
+                    key: format!("{}/localhost.key", directory),
+                    server_names: Some(vec!["localhost".to_string()]),
+                },
+                ConfigTlsCertificate {
+                    cert: format!("{}/other.test.pem", directory),
+                    key: format!("{}/other.test.key", directory),
+                    server_names: Some(vec!["other.test".to_string(), "*.other.test".to_string()]),
+                },
+            ],
+        }
+    }
+
+    // Connects to a TLS server trusting the test CA.
+    pub(crate) async fn connect(
+        addr: &str,
+        server_name: &str,
+    ) -> tokio_rustls::client::TlsStream<TcpStream> {
+        let mut roots = RootCertStore::empty();
+        roots.add(pem("tests/certs/ca.pem").into()).unwrap();
+
+        let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        TlsConnector::from(Arc::new(config))
+            .connect(
+                ServerName::try_from(server_name.to_string()).unwrap(),
+                stream,
+            )
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_certificate_for() {
+        let store = super::CertificateStore::new(&config("tests/certs")).unwrap();
+        let localhost = pem("tests/certs/localhost.pem");
+        let other = pem("tests/certs/other.test.pem");
+
+        assert_eq!(
+            leaf(&store.certificate_for(Some("localhost")).unwrap()),
+            localhost
+        );
+        assert_eq!(
+            leaf(&store.certificate_for(Some("other.test")).unwrap()),
+            other
+        );
+        assert_eq!(
+            leaf(&store.certificate_for(Some("www.OTHER.test")).unwrap()),
+            other
+        );
+        assert_eq!(
+            leaf(&store.certificate_for(Some("unknown")).unwrap()),
+            localhost
+        );
+        assert_eq!(leaf(&store.certificate_for(None).unwrap()), localhost);
+
+        let mut invalid = config("tests/certs");
+        invalid.certificates[1].key = "tests/certs/localhost.key".to_string();
+        let error = super::CertificateStore::new(&invalid).unwrap_err();
+        assert_eq!(error.field, "certificates[1].key");
+
+        invalid.certificates[0].cert = "tests/certs/missing.pem".to_string();
+        let error = super::CertificateStore::new(&invalid).unwrap_err();
+        assert_eq!(error.field, "certificates[0].cert");
+    }
+
+    #[tokio::test]
+    async fn test_certificate_reload() {
+        let directory = std::env::temp_dir().join("social-routing-test-certs");
+        std::fs::create_dir_all(&directory).unwrap();
+        for file in [
+            "localhost.pem",
+            "localhost.key",
+            "other.test.pem",
+            "other.test.key",
+        ] {
+            std::fs::copy(format!("tests/certs/{}", file), directory.join(file)).unwrap();
+        }
+
+        let store =
+            Arc::new(super::CertificateStore::new(&config(directory.to_str().unwrap())).unwrap());
+        store.watch();
+
+        let localhost = pem("tests/certs/localhost.pem");
+        let other = pem("tests/certs/other.test.pem");
+        assert_eq!(
+            leaf(&store.certificate_for(Some("localhost")).unwrap()),
+            localhost
+        );
+
+        // The localhost certificate is replaced.
+        std::fs::copy(
+            "tests/certs/other.test.key",
+            directory.join("localhost.key"),
+        )
+        .unwrap();
+        std::fs::copy(
+            "tests/certs/other.test.pem",
+            directory.join("localhost.pem"),
+        )
+        .unwrap();
+
+        let mut reloaded = false;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if leaf(&store.certificate_for(Some("localhost")).unwrap()) == other {
+                reloaded = true;
+                break;
+            }
+        }
+        assert!(reloaded);
+    }
+
+    #[tokio::test]
+    async fn test_tls_server() {
+        let config = Config::create_from_filename("tests/configs/009_tls.yaml").unwrap();
+        tokio::spawn(async move { crate::server::run(&config).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        for (name, expected) in [
+            ("localhost", "tests/certs/localhost.pem"),
+            ("other.test", "tests/certs/other.test.pem"),
+        ] {
+            let stream = connect("127.0.0.1:5910", name).await;
+            let (_, session) = stream.get_ref();
+            assert_eq!(
+                session.peer_certificates().unwrap()[0].to_vec(),
+                pem(expected)
+            );
+
+            let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+            tokio::spawn(connection);
+
+            let resp = sender
+                .send_request(
+                    hyper::Request::get("/test1")
+                        .header("host", name)
+                        .body(hyper::Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), hyper::StatusCode::TEMPORARY_REDIRECT);
+            assert_eq!(resp.headers()["location"], "test");
+        }
+    }
+}
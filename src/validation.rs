@@ -0,0 +1,340 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::config::{ConfigListener, ConfigRule};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+// Where a rule, and each of its fields, has been written in the YAML file.
+#[derive(Debug, Clone, Default)]
+pub struct RuleLocations {
+    pub rule: Option<Location>,
+    pub fields: HashMap<String, Location>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Issue {
+    pub rule: Option<String>,
+    pub listener: Option<String>,
+    pub field: Option<String>,
+    pub reason: String,
+    pub location: Option<Location>,
+}
+
+impl Issue {
+    pub fn new(reason: impl Into<String>, location: Option<Location>) -> Issue {
+        Issue {
+            rule: None,
+            listener: None,
+            field: None,
+            reason: reason.into(),
+            location,
+        }
+    }
+
+    // An issue with a field of a listener, such as `tls.certificates[0].key`.
+    pub fn listener(listener: &ConfigListener, field: &str, reason: impl Into<String>) -> Issue {
+        Issue {
+            rule: None,
+            listener: Some(listener.bind.clone()),
+            field: Some(field.to_string()),
+            reason: reason.into(),
+            location: None,
+        }
+    }
+
+    pub fn rule(rule: &ConfigRule, field: &str, reason: impl Into<String>) -> Issue {
+        Issue {
+            rule: Some(rule.name.clone()),
+            listener: None,
+            field: Some(field.to_string()),
+            reason: reason.into(),
+            location: rule
+                .locations
+                .fields
+                .get(field)
+                .or(rule.locations.rule.as_ref())
+                .copied(),
+        }
+    }
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if let Some(location) = self.location {
+            write!(f, "line {}, column {}: ", location.line, location.column)?;
+        }
+        if let Some(rule) = &self.rule {
+            write!(f, "rule `{}`: ", rule)?;
+        }
+        if let Some(listener) = &self.listener {
+            write!(f, "listener `{}`: ", listener)?;
+        }
+        if let Some(field) = &self.field {
+            write!(f, "`{}`: ", field)?;
+        }
+        write!(f, "{}", self.reason)
+    }
+}
+
+// All the problems found while validating a configuration.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    pub fn new() -> Report {
+        Report::default()
+    }
+
+    pub fn push(&mut self, issue: Issue) {
+        self.issues.push(issue);
+    }
+
+    pub fn merge(&mut self, other: Report) {
+        self.issues.extend(other.issues);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    // Returns `value` if no issues have been found.
+    pub fn check<T>(self, value: T) -> Result<T, Report> {
+        if self.is_empty() {
+            Ok(value)
+        } else {
+            Err(self)
+        }
+    }
+
+    pub fn log(&self, filename: &str) {
+        for issue in &self.issues {
+            tracing::error!(target: "Config",
+                filename=filename,
+                rule=issue.rule,
+                listener=issue.listener,
+                field=issue.field,
+                line=issue.location.map(|l| l.line),
+                column=issue.location.map(|l| l.column),
+                "{}", issue.reason);
+        }
+    }
+}
+
+impl std::error::Error for Report {}
+
+impl From<Issue> for Report {
+    fn from(issue: Issue) -> Report {
+        Report {
+            issues: vec![issue],
+        }
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, issue) in self.issues.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+enum Container {
+    // The key of the value being parsed, if any.
+    Mapping(Option<String>),
+    // The index of the next item.
+    Sequence(usize),
+}
+
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+// Collects the location of each rule from the YAML events, because serde does
+// not keep track of where values come from.
+#[derive(Default)]
+struct LocationCollector {
+    containers: Vec<Container>,
+    path: Vec<Segment>,
+    rules: Vec<RuleLocations>,
+}
+
+impl LocationCollector {
+    fn rule_index(&self) -> Option<usize> {
+        match self.path.as_slice() {
+            [Segment::Key(key), Segment::Index(index)] if key == "rules" => Some(*index),
+            _ => None,
+        }
+    }
+
+    fn rule_mut(&mut self, index: usize) -> &mut RuleLocations {
+        if self.rules.len() <= index {
+            self.rules.resize(index + 1, RuleLocations::default());
+        }
+        &mut self.rules[index]
+    }
+
+    // The segment of the value starting now in the current container.
+    fn next_segment(&mut self) -> Option<Segment> {
+        match self.containers.last_mut() {
+            Some(Container::Mapping(key)) => key.take().map(Segment::Key),
+            Some(Container::Sequence(index)) => {
+                *index += 1;
+                Some(Segment::Index(*index - 1))
+            }
+            None => None,
+        }
+    }
+
+    fn start_container(&mut self, container: Container) {
+        if let Some(segment) = self.next_segment() {
+            self.path.push(segment);
+        }
+        self.containers.push(container);
+    }
+
+    fn end_container(&mut self) {
+        self.containers.pop();
+        if !self.containers.is_empty() {
+            self.path.pop();
+        }
+    }
+}
+
+impl MarkedEventReceiver for LocationCollector {
+    fn on_event(&mut self, event: Event, marker: Marker) {
+        match event {
+            Event::MappingStart(_) => self.start_container(Container::Mapping(None)),
+            Event::SequenceStart(_) => self.start_container(Container::Sequence(0)),
+            Event::MappingEnd | Event::SequenceEnd => self.end_container(),
+            Event::Scalar(value, ..) => {
+                if let Some(Container::Mapping(key @ None)) = self.containers.last_mut() {
+                    *key = Some(value.clone());
+                    if let Some(index) = self.rule_index() {
+                        // A rule starts where its first field is written.
+                        let rule = self.rule_mut(index);
+                        rule.rule.get_or_insert(location(marker));
+                        rule.fields.insert(value, location(marker));
+                    }
+                } else {
+                    self.next_segment();
+                }
+            }
+            Event::Alias(_) => {
+                self.next_segment();
+            }
+            _ => {}
+        }
+    }
+}
+
+fn location(marker: Marker) -> Location {
+    Location {
+        line: marker.line(),
+        column: marker.col() + 1,
+    }
+}
+
+// Returns the locations of each rule of the configuration. Errors are ignored
+// here: serde reports them when parsing the same content.
+pub fn rule_locations(content: &str) -> Vec<RuleLocations> {
+    let mut collector = LocationCollector::default();
+    let mut parser = Parser::new(content.chars());
+    let _ = parser.load(&mut collector, false);
+    collector.rules
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use crate::errors::Error;
+    use crate::routers;
+
+    #[test]
+    fn test_rule_locations() {
+        let content = std::fs::read_to_string("tests/configs/004_redirect_action.yaml").unwrap();
+        let locations = super::rule_locations(&content);
+        assert_eq!(locations.len(), 4);
+        assert_eq!(locations[0].rule.unwrap().line, 5);
+        assert_eq!(locations[1].fields["redirect_to"].line, 13);
+        assert_eq!(locations[1].fields["redirect_to"].column, 5);
+        assert_eq!(locations[3].fields["redirect_status"].line, 23);
+    }
+
+    #[test]
+    fn test_report_all_issues() {
+        let config = Config::create_from_filename("tests/configs/007_invalid_rules.yaml").unwrap();
+        let report = routers::routers(&config.rules, &config.server).unwrap_err();
+
+        let issues: Vec<_> = report
+            .issues
+            .iter()
+            .map(|i| {
+                (
+                    i.rule.as_deref().unwrap(),
+                    i.field.as_deref().unwrap(),
+                    i.location.unwrap().line,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            issues,
+            vec![
+                ("invalid method", "method", 6),
+                ("missing redirect", "redirect_to", 10),
+                ("not a redirect status", "redirect_status", 17),
+                ("missing proxy url", "proxy_url", 20),
+                ("unknown action", "action", 25),
+                ("invalid method", "name", 27),
+                ("invalid path", "path", 33),
+                ("invalid host", "host", 38),
+                ("unknown header match", "headers", 43),
+                ("invalid header regex", "headers", 51),
+                ("wildcard accept", "accept", 59),
+                ("unsupported query match", "query", 66),
+                ("empty match block", "match", 73),
+                ("invalid method in a match block", "match", 79),
+                ("invalid method in a list", "method", 85),
+                ("no methods excluded", "method", 90),
+                ("too many retries", "retry", 98),
+                ("negative timeout", "timeouts", 104),
+                ("rewrite and strip", "proxy_path", 110),
+                ("empty proxy host", "proxy_host", 117),
+                ("client certificate without key", "proxy_tls", 122),
+                ("unsupported upstream scheme", "proxy_url", 127),
+                ("h2 without tls", "upstream_protocol", 132),
+                ("no upgrade idle timeout", "upgrade", 137),
+                ("unknown upstream protocol", "upstream_protocol", 144),
+                ("upgrade over h2c", "upgrade", 150),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_report_parse_error() {
+        match Config::create_from_filename("tests/configs/008_invalid_yaml.yaml") {
+            Err(Error::InvalidConfig(report)) => {
+                assert_eq!(report.issues.len(), 1);
+                assert_eq!(report.issues[0].location.unwrap().line, 5);
+            }
+            _ => panic!("the configuration should be invalid"),
+        }
+    }
+}